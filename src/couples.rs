@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+use crate::input::{
+    couples_blocking_pair, CoupleInput, CouplesBlockingPair, ProposerId, ProposerInput,
+    ResponderId, ResponderInput,
+};
+
+#[derive(Debug)]
+pub enum CouplesOutcome {
+    Stable(HashMap<ProposerId, ResponderId>),
+    // We ran out of iterations, and this is the matching we ended up with along with
+    // something that still blocks it
+    Unstable {
+        matching: HashMap<ProposerId, ResponderId>,
+        blocking: CouplesBlockingPair,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Applicant {
+    Single(usize),
+    Couple(usize),
+}
+
+struct State<'a> {
+    singles: &'a [ProposerInput],
+    couples: &'a [CoupleInput],
+    responders: &'a [ResponderInput],
+    // Who every Proposer applies with
+    applicants: Vec<Applicant>,
+    // Number of entries that have not rejected each applicant yet. The next entry to
+    // apply to is preferences[remaining - 1]
+    single_remaining: Vec<usize>,
+    couple_remaining: Vec<usize>,
+    assignment: Vec<Option<ResponderId>>,
+    accepted: Vec<Vec<ProposerId>>,
+    // Mapping from ResponderId -> ProposerId -> preference, or None if the Proposer is
+    // not acceptable
+    preferences_by_proposer: Vec<Vec<Option<usize>>>,
+    stack: Vec<Applicant>,
+}
+
+impl<'a> State<'a> {
+    fn new(
+        singles: &'a [ProposerInput],
+        couples: &'a [CoupleInput],
+        responders: &'a [ResponderInput],
+    ) -> Result<Self> {
+        let num_proposers = singles.len() + 2 * couples.len();
+        let mut applicants: Vec<Option<Applicant>> = vec![None; num_proposers];
+
+        let mut add = |p: ProposerId, applicant: Applicant| -> Result<()> {
+            match applicants.get_mut(p as usize) {
+                Some(slot) if slot.is_none() => *slot = Some(applicant),
+                _ => bail!("received invalid or duplicate proposer: {}", p),
+            }
+            Ok(())
+        };
+
+        for (index, p) in singles.iter().enumerate() {
+            add(p.id, Applicant::Single(index))?;
+            if p.preferences
+                .iter()
+                .any(|r| *r as usize >= responders.len())
+            {
+                bail!(
+                    "received invalid preferences in proposer: {} preferences: {:?}",
+                    p.id,
+                    p.preferences
+                );
+            }
+        }
+
+        for (index, c) in couples.iter().enumerate() {
+            add(c.members.0, Applicant::Couple(index))?;
+            add(c.members.1, Applicant::Couple(index))?;
+            if c.preferences
+                .iter()
+                .any(|(x, y)| *x as usize >= responders.len() || *y as usize >= responders.len())
+            {
+                bail!(
+                    "received invalid preferences in couple: {:?} preferences: {:?}",
+                    c.members,
+                    c.preferences
+                );
+            }
+        }
+
+        let mut preferences_by_proposer = Vec::new();
+        for r in responders.iter() {
+            let mut preferences: Vec<Option<usize>> = vec![None; num_proposers];
+            for (index, p) in r.preferences.iter().enumerate() {
+                match preferences.get_mut(*p as usize) {
+                    Some(slot) if slot.is_none() => *slot = Some(index),
+                    _ => bail!(
+                        "received invalid preferences in responder: {} preferences: {:?}",
+                        r.id,
+                        r.preferences
+                    ),
+                }
+            }
+            preferences_by_proposer.push(preferences);
+        }
+
+        // There are as many slots as Proposers, and add rejects ids that are out of range
+        // or already taken, so every slot has been filled
+        let applicants: Vec<_> = applicants
+            .into_iter()
+            .map(|a| a.expect("applicant known to fill every proposer slot"))
+            .collect();
+        let mut stack: Vec<_> = (0..singles.len()).map(Applicant::Single).collect();
+        stack.extend((0..couples.len()).map(Applicant::Couple));
+        stack.reverse();
+
+        Ok(State {
+            singles,
+            couples,
+            responders,
+            applicants,
+            single_remaining: singles.iter().map(|p| p.preferences.len()).collect(),
+            couple_remaining: couples.iter().map(|c| c.preferences.len()).collect(),
+            assignment: vec![None; num_proposers],
+            accepted: vec![Vec::new(); responders.len()],
+            preferences_by_proposer,
+            stack,
+        })
+    }
+
+    fn accepts(&self, r: ResponderId, incoming: &[ProposerId]) -> bool {
+        self.responders[r as usize].would_accept(&self.accepted[r as usize], incoming)
+    }
+
+    fn unassign(&mut self, p: ProposerId) {
+        if let Some(r) = self.assignment[p as usize].take() {
+            self.accepted[r as usize].retain(|x| *x != p);
+        }
+    }
+
+    // Take p in at r, and evict the least preferred Proposers until r is within capacity
+    fn assign(&mut self, p: ProposerId, r: ResponderId) {
+        self.assignment[p as usize] = Some(r);
+        self.accepted[r as usize].push(p);
+
+        while self.accepted[r as usize].len() > self.responders[r as usize].capacity {
+            let preferences = &self.preferences_by_proposer[r as usize];
+            let worst = *self.accepted[r as usize]
+                .iter()
+                .min_by_key(|x| preferences[**x as usize])
+                .expect("responder known to be over capacity");
+            self.evict(worst);
+        }
+    }
+
+    // A Proposer was rejected from where it was assigned. Couples are rejected together,
+    // which can leave a spot open for someone that was rejected earlier
+    fn evict(&mut self, p: ProposerId) {
+        let applicant = self.applicants[p as usize];
+        match applicant {
+            Applicant::Single(index) => {
+                self.unassign(p);
+                self.single_remaining[index] -= 1;
+            }
+            Applicant::Couple(index) => {
+                let (a, b) = self.couples[index].members;
+                self.unassign(a);
+                self.unassign(b);
+                self.couple_remaining[index] -= 1;
+            }
+        }
+        self.stack.push(applicant);
+    }
+
+    // Apply down the preference list until someone takes the applicant
+    fn apply(&mut self, applicant: Applicant) {
+        match applicant {
+            Applicant::Single(index) => {
+                let singles = self.singles;
+                let p = &singles[index];
+                while self.single_remaining[index] > 0 {
+                    let r = p.preferences[self.single_remaining[index] - 1];
+                    if self.accepts(r, &[p.id]) {
+                        self.assign(p.id, r);
+                        return;
+                    }
+                    self.single_remaining[index] -= 1;
+                }
+            }
+            Applicant::Couple(index) => {
+                let couples = self.couples;
+                let c = &couples[index];
+                let (a, b) = c.members;
+                while self.couple_remaining[index] > 0 {
+                    let (x, y) = c.preferences[self.couple_remaining[index] - 1];
+                    let accepted = if x == y {
+                        self.accepts(x, &[a, b])
+                    } else {
+                        self.accepts(x, &[a]) && self.accepts(y, &[b])
+                    };
+
+                    if accepted {
+                        self.assign(a, x);
+                        self.assign(b, y);
+                        return;
+                    }
+                    self.couple_remaining[index] -= 1;
+                }
+            }
+        }
+    }
+
+    // Send the applicant in a blocking pair back to the entry it blocks with
+    fn repair(&mut self, blocking: CouplesBlockingPair) {
+        match blocking {
+            CouplesBlockingPair::Single {
+                proposer,
+                responder,
+            } => {
+                let index = match self.applicants[proposer as usize] {
+                    Applicant::Single(index) => index,
+                    Applicant::Couple(_) => unreachable!("blocking proposer known to be single"),
+                };
+                let position = self.singles[index]
+                    .preferences
+                    .iter()
+                    .position(|r| *r == responder)
+                    .expect("blocking responder known to be acceptable");
+
+                self.unassign(proposer);
+                self.single_remaining[index] = position + 1;
+                self.stack.push(Applicant::Single(index));
+            }
+            CouplesBlockingPair::Couple {
+                members,
+                responders,
+            } => {
+                let index = match self.applicants[members.0 as usize] {
+                    Applicant::Couple(index) => index,
+                    Applicant::Single(_) => unreachable!("blocking members known to be a couple"),
+                };
+                let position = self.couples[index]
+                    .preferences
+                    .iter()
+                    .position(|pair| *pair == responders)
+                    .expect("blocking responders known to be acceptable");
+
+                self.unassign(members.0);
+                self.unassign(members.1);
+                self.couple_remaining[index] = position + 1;
+                self.stack.push(Applicant::Couple(index));
+            }
+        }
+    }
+
+    fn matching(&self) -> HashMap<ProposerId, ResponderId> {
+        self.assignment
+            .iter()
+            .enumerate()
+            .filter_map(|(p, r)| r.map(|r| (p as ProposerId, r)))
+            .collect()
+    }
+}
+
+// Stable matchings with couples might not exist, and finding one is NP-hard, so this
+// follows the Roth-Peranson approach. Singles and couples apply one at a time, and
+// couples rejected from one of their positions withdraw from the other. Withdrawing can
+// leave a spot open for someone rejected earlier, so once everyone is placed we look for
+// a blocking pair and send the applicant in it back to apply again. We give up after
+// resolving iteration_limit blocking pairs, since this can cycle forever
+pub fn stable_matching(
+    singles: &[ProposerInput],
+    couples: &[CoupleInput],
+    responders: &[ResponderInput],
+    iteration_limit: usize,
+) -> Result<CouplesOutcome> {
+    let mut state = State::new(singles, couples, responders)?;
+    let mut iterations = 0;
+
+    loop {
+        while let Some(applicant) = state.stack.pop() {
+            state.apply(applicant);
+        }
+
+        let matching = state.matching();
+        let blocking = match couples_blocking_pair(singles, couples, responders, &matching) {
+            Some(blocking) => blocking,
+            None => return Ok(CouplesOutcome::Stable(matching)),
+        };
+
+        if iterations == iteration_limit {
+            return Ok(CouplesOutcome::Unstable { matching, blocking });
+        }
+
+        iterations += 1;
+        state.repair(blocking);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::CouplesOutcome;
+    use crate::input::{couples_blocking_pair, validate_couples_matching, Side};
+
+    #[test]
+    fn couples_test() {
+        let mut rng = rand::thread_rng();
+        for num_singles in 0..20 {
+            for num_couples in 0..5 {
+                for num_responders in 1..6 {
+                    let (singles, couples, responders) = crate::input::random_couples_input(
+                        num_singles,
+                        num_couples,
+                        num_responders,
+                        3,
+                        &mut rng,
+                    );
+
+                    let outcome =
+                        super::stable_matching(&singles, &couples, &responders, 100).unwrap();
+
+                    match outcome {
+                        CouplesOutcome::Stable(matching) => {
+                            assert_eq!(
+                                validate_couples_matching(
+                                    &singles,
+                                    &couples,
+                                    &responders,
+                                    &matching
+                                ),
+                                true
+                            );
+
+                            // Without couples this is just the Hospitals/Residents problem
+                            if num_couples == 0 {
+                                let expected = crate::hospitals_residents::stable_matching(
+                                    &singles,
+                                    &responders,
+                                    Side::Proposer,
+                                )
+                                .unwrap();
+                                let expected: HashMap<_, _> = expected
+                                    .into_iter()
+                                    .flat_map(|(r, ps)| ps.into_iter().map(move |p| (p, r)))
+                                    .collect();
+                                assert_eq!(matching, expected);
+                            }
+                        }
+                        CouplesOutcome::Unstable { matching, blocking } => {
+                            assert_ne!(num_couples, 0);
+                            assert_eq!(
+                                validate_couples_matching(
+                                    &singles,
+                                    &couples,
+                                    &responders,
+                                    &matching
+                                ),
+                                false
+                            );
+                            assert_eq!(
+                                couples_blocking_pair(&singles, &couples, &responders, &matching),
+                                Some(blocking)
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_couples_test() {
+        use crate::input::{CoupleInput, ProposerInput, ResponderInput};
+
+        let responders = vec![ResponderInput::with_capacity(0, vec![0, 1, 2], 2)];
+
+        // Proposer ids have to cover [0, n) exactly once between the singles and couples
+        let singles = vec![ProposerInput::new(0, vec![0])];
+        let couples = vec![CoupleInput::new((1, 3), vec![(0, 0)])];
+        assert_eq!(
+            super::stable_matching(&singles, &couples, &responders, 100).is_err(),
+            true
+        );
+
+        let couples = vec![CoupleInput::new((0, 1), vec![(0, 0)])];
+        assert_eq!(
+            super::stable_matching(&singles, &couples, &responders, 100).is_err(),
+            true
+        );
+
+        let couples = vec![CoupleInput::new((1, 2), vec![(0, 0)])];
+        assert_eq!(
+            super::stable_matching(&singles, &couples, &responders, 100).is_ok(),
+            true
+        );
+    }
+}
@@ -10,9 +10,10 @@ use timely::dataflow::ProbeHandle;
 use timely::dataflow::Scope;
 use timely::order::Product;
 
+use crate::input::Side;
 use crate::stable_marriage::{Suited, Suitor};
 
-pub fn generate_match(suitors: Vec<Suitor>, suiteds: Vec<Suited>) {
+pub fn generate_match(suitors: Vec<Suitor>, suiteds: Vec<Suited>, side: Side) {
     timely::execute_from_args(std::env::args(), move |worker| {
         let mut probe = ProbeHandle::new();
 
@@ -20,7 +21,13 @@ pub fn generate_match(suitors: Vec<Suitor>, suiteds: Vec<Suited>) {
             let (suitors_input, suitors) = scope.new_collection();
             let (suiteds_input, suiteds) = scope.new_collection();
 
-            let result = generate_match_dataflow_v2(&suitors, &suiteds);
+            // Suiteds propose by taking the place of the Suitors, and the result is
+            // swapped back into (suitor, suited) pairs
+            let result = match side {
+                Side::Proposer => generate_match_dataflow_v2(&suitors, &suiteds),
+                Side::Responder => generate_match_dataflow_v2(&suiteds, &suitors)
+                    .map(|(suited, suitor)| (suitor, suited)),
+            };
 
             result
                 .inspect(|x| println!("result: {:?}", x))
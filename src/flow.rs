@@ -0,0 +1,189 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::ops::{Add, Sub};
+
+// Anything we can push through a network. Augmenting along shortest paths terminates
+// no matter what the capacities are, so any totally ordered group works
+pub trait Capacity: Clone + Ord + Add<Output = Self> + Sub<Output = Self> {
+    fn zero() -> Self;
+}
+
+impl Capacity for i64 {
+    fn zero() -> Self {
+        0
+    }
+}
+
+// Vectors compared lexicographically, where missing entries count as zero
+#[derive(Debug, Clone, Default)]
+pub struct Lexicographic(pub Vec<i64>);
+
+impl Lexicographic {
+    fn get(&self, index: usize) -> i64 {
+        self.0.get(index).cloned().unwrap_or(0)
+    }
+}
+
+impl Add for Lexicographic {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        let len = std::cmp::max(self.0.len(), other.0.len());
+        Lexicographic((0..len).map(|i| self.get(i) + other.get(i)).collect())
+    }
+}
+
+impl Sub for Lexicographic {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        let len = std::cmp::max(self.0.len(), other.0.len());
+        Lexicographic((0..len).map(|i| self.get(i) - other.get(i)).collect())
+    }
+}
+
+impl Ord for Lexicographic {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = std::cmp::max(self.0.len(), other.0.len());
+        (0..len)
+            .map(|i| self.get(i).cmp(&other.get(i)))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Lexicographic {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Lexicographic {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Lexicographic {}
+
+impl Capacity for Lexicographic {
+    fn zero() -> Self {
+        Lexicographic(Vec::new())
+    }
+}
+
+#[derive(Debug)]
+struct Edge<C> {
+    to: usize,
+    // Capacity left on this edge. Every edge is stored next to its reverse edge, so
+    // the reverse of edges[i] is edges[i ^ 1]
+    residual: C,
+}
+
+#[derive(Debug, Default)]
+pub struct FlowNetwork<C> {
+    edges: Vec<Edge<C>>,
+    adjacency: Vec<Vec<usize>>,
+}
+
+impl<C: Capacity> FlowNetwork<C> {
+    pub fn new(nodes: usize) -> Self {
+        FlowNetwork {
+            edges: Vec::new(),
+            adjacency: vec![Vec::new(); nodes],
+        }
+    }
+
+    // Returns a handle for looking up the flow on the edge later
+    pub fn add_edge(&mut self, from: usize, to: usize, capacity: C) -> usize {
+        let edge = self.edges.len();
+        self.adjacency[from].push(edge);
+        self.edges.push(Edge {
+            to,
+            residual: capacity,
+        });
+        self.adjacency[to].push(edge + 1);
+        self.edges.push(Edge {
+            to: from,
+            residual: C::zero(),
+        });
+        edge
+    }
+
+    // Flow pushed along an edge, which is exactly the residual capacity of its reverse
+    pub fn flow(&self, edge: usize) -> C {
+        self.edges[edge ^ 1].residual.clone()
+    }
+
+    // Edmonds-Karp: keep pushing flow along shortest augmenting paths
+    pub fn max_flow(&mut self, source: usize, sink: usize) -> C {
+        let mut total = C::zero();
+
+        loop {
+            // The edge used to reach every node in the breadth first search
+            let mut parent: Vec<Option<usize>> = vec![None; self.adjacency.len()];
+            let mut queue = VecDeque::new();
+            queue.push_back(source);
+
+            while let Some(node) = queue.pop_front() {
+                for edge in self.adjacency[node].iter() {
+                    let to = self.edges[*edge].to;
+                    if self.edges[*edge].residual > C::zero()
+                        && to != source
+                        && parent[to].is_none()
+                    {
+                        parent[to] = Some(*edge);
+                        queue.push_back(to);
+                    }
+                }
+            }
+
+            if parent[sink].is_none() {
+                return total;
+            }
+
+            // Find the bottleneck along the path, and then push that much flow along it
+            let mut bottleneck: Option<C> = None;
+            let mut node = sink;
+            while let Some(edge) = parent[node] {
+                let residual = self.edges[edge].residual.clone();
+                bottleneck = Some(match bottleneck {
+                    Some(b) => std::cmp::min(b, residual),
+                    None => residual,
+                });
+                node = self.edges[edge ^ 1].to;
+            }
+            let bottleneck = bottleneck.expect("path known to be non-empty");
+
+            let mut node = sink;
+            while let Some(edge) = parent[node] {
+                self.edges[edge].residual = self.edges[edge].residual.clone() - bottleneck.clone();
+                self.edges[edge ^ 1].residual =
+                    self.edges[edge ^ 1].residual.clone() + bottleneck.clone();
+                node = self.edges[edge ^ 1].to;
+            }
+
+            total = total + bottleneck;
+        }
+    }
+
+    // Nodes reachable from source in the residual network. After computing a maximum
+    // flow these are the source side of a minimum cut
+    pub fn reachable(&self, source: usize) -> Vec<bool> {
+        let mut reachable = vec![false; self.adjacency.len()];
+        let mut stack = vec![source];
+        reachable[source] = true;
+
+        while let Some(node) = stack.pop() {
+            for edge in self.adjacency[node].iter() {
+                let to = self.edges[*edge].to;
+                if self.edges[*edge].residual > C::zero() && !reachable[to] {
+                    reachable[to] = true;
+                    stack.push(to);
+                }
+            }
+        }
+
+        reachable
+    }
+}
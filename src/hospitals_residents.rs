@@ -0,0 +1,574 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::iter::FromIterator;
+
+use anyhow::{bail, Result};
+
+use crate::input::{
+    validate_input, ProposerId, ProposerInput, ResponderId, ResponderInput, Side, UnmetLowerQuotas,
+};
+
+#[derive(Debug)]
+pub struct ClosedResponderMatching {
+    // Mapping from ResponderId : set(ProposerId) for every Responder that stayed open
+    pub assignment: HashMap<ResponderId, BTreeSet<ProposerId>>,
+    // Responders that could not reach their lower quota, in the order they were closed
+    pub closed: Vec<ResponderId>,
+}
+
+#[derive(Debug, Default, Eq, Hash, PartialEq)]
+pub struct Proposer<'a> {
+    id: ProposerId,
+    // List of Responders ordered by ascending preference
+    preferences: &'a [ResponderId],
+    capacity: usize,
+    // Number of Responders this Proposer has not proposed to yet. The next
+    // Responder to propose to is preferences[remaining - 1]
+    remaining: usize,
+    // Number of proposals that have not been rejected yet
+    held: usize,
+}
+
+impl<'a> Proposer<'a> {
+    pub fn new(
+        id: ProposerId,
+        preferences: &'a [ResponderId],
+        capacity: usize,
+        num_proposers: usize,
+        num_responders: usize,
+    ) -> Result<Self> {
+        if id as usize >= num_proposers {
+            bail!(
+                "received invalid proposer input id: {} preferences: {:?}",
+                id,
+                preferences
+            );
+        }
+
+        // Proposing to the same Responder twice would hold a slot it can never release
+        let mut seen = vec![false; num_responders];
+        for r in preferences.iter() {
+            if *r as usize >= num_responders {
+                bail!(
+                    "received invalid preferences in proposer: {} preferences: {:?}",
+                    id,
+                    preferences
+                );
+            }
+
+            if seen[*r as usize] {
+                bail!(
+                    "received duplicate preferences in proposer: {} preferences: {:?}",
+                    id,
+                    preferences
+                );
+            }
+            seen[*r as usize] = true;
+        }
+
+        Ok(Proposer {
+            id,
+            preferences,
+            capacity,
+            remaining: preferences.len(),
+            held: 0,
+        })
+    }
+
+    // Propose to the most preferred Responders we have not proposed to yet, until we
+    // either have as many proposals out as we have capacity for or run out of Responders
+    fn get_preferences(&mut self) -> Vec<ResponderId> {
+        let mut preferences = Vec::new();
+
+        while self.held < self.capacity && self.remaining > 0 {
+            self.remaining -= 1;
+            self.held += 1;
+            preferences.push(self.preferences[self.remaining]);
+        }
+
+        preferences
+    }
+
+    fn add_rejection(&mut self) -> Result<()> {
+        if self.held > 0 {
+            Ok(self.held -= 1)
+        } else {
+            bail!("proposer {} received too many rejections", self.id);
+        }
+    }
+}
+
+#[derive(Debug, Default, Eq, Hash, PartialEq)]
+pub struct Responder {
+    id: ResponderId,
+    capacity: usize,
+    // Mapping from ProposerId -> preference, or None if the Proposer is not acceptable
+    // Relies on ProposerIds being in the domain [0, n) where n is the number of Proposers
+    preferences_by_proposer: Vec<Option<usize>>,
+    proposals: BTreeSet<ProposerId>,
+    accepted: BTreeSet<ProposerId>,
+}
+
+impl Responder {
+    pub fn new(
+        id: ResponderId,
+        preferences: &[ProposerId],
+        capacity: usize,
+        num_responders: usize,
+        num_proposers: usize,
+    ) -> Result<Self> {
+        if id as usize >= num_responders {
+            bail!(
+                "received invalid responder input id: {} preferences: {:?}",
+                id,
+                preferences
+            );
+        }
+
+        let mut preferences_by_proposer: Vec<Option<usize>> = vec![None; num_proposers];
+
+        for (index, p) in preferences.iter().enumerate() {
+            if *p as usize >= num_proposers {
+                bail!(
+                    "received invalid preferences in responder: {} preferences: {:?}",
+                    id,
+                    preferences
+                );
+            }
+
+            if preferences_by_proposer[*p as usize].is_some() {
+                bail!(
+                    "received duplicate preferences in responder: {} preferences: {:?}",
+                    id,
+                    preferences
+                );
+            }
+            preferences_by_proposer[*p as usize] = Some(index);
+        }
+
+        Ok(Responder {
+            id,
+            capacity,
+            preferences_by_proposer,
+            proposals: BTreeSet::new(),
+            accepted: BTreeSet::new(),
+        })
+    }
+
+    fn add_proposal(&mut self, proposer: ProposerId) {
+        self.proposals.insert(proposer);
+    }
+
+    fn reject(&mut self) -> Vec<ProposerId> {
+        let proposals = std::mem::replace(&mut self.proposals, BTreeSet::new());
+
+        // Order every acceptable proposal from most to least preferred, and reject
+        // the ones we could never accept outright
+        let mut rejections = Vec::new();
+        let mut acceptable = Vec::new();
+        for p in proposals.into_iter() {
+            match self.preferences_by_proposer[p as usize] {
+                Some(preference) => acceptable.push((preference, p)),
+                None => rejections.push(p),
+            }
+        }
+        acceptable.sort_by(|a, b| b.cmp(a));
+
+        // Tentatively accept as many proposals as we have capacity for
+        let split = std::cmp::min(self.capacity, acceptable.len());
+        rejections.extend(acceptable.drain(split..).map(|(_, p)| p));
+        self.accepted = acceptable.into_iter().map(|(_, p)| p).collect();
+
+        // We want to preserve the invariant that proposals always contains the proposals
+        // we tentatively accepted + future proposals
+        self.proposals = self.accepted.clone();
+
+        rejections
+    }
+}
+
+pub fn stable_matching(
+    proposers_input: &[ProposerInput],
+    responders_input: &[ResponderInput],
+    side: Side,
+) -> Result<HashMap<ResponderId, BTreeSet<ProposerId>>> {
+    validate_input(proposers_input, responders_input)?;
+
+    let num_proposers = proposers_input.len();
+    let num_responders = responders_input.len();
+    match side {
+        Side::Proposer => {
+            let proposers: Vec<_> = proposers_input
+                .iter()
+                .map(|p| Proposer::new(p.id, &p.preferences, 1, num_proposers, num_responders))
+                .collect::<Result<_>>()?;
+            let responders: Vec<_> = responders_input
+                .iter()
+                .map(|r| {
+                    Responder::new(
+                        r.id,
+                        &r.preferences,
+                        r.capacity,
+                        num_responders,
+                        num_proposers,
+                    )
+                })
+                .collect::<Result<_>>()?;
+
+            deferred_acceptance(proposers, responders)
+        }
+        Side::Responder => {
+            // Responders propose to as many Proposers as they have capacity for, and every
+            // Proposer holds on to the best one
+            let proposers: Vec<_> = responders_input
+                .iter()
+                .map(|r| {
+                    Proposer::new(
+                        r.id,
+                        &r.preferences,
+                        r.capacity,
+                        num_responders,
+                        num_proposers,
+                    )
+                })
+                .collect::<Result<_>>()?;
+            let responders: Vec<_> = proposers_input
+                .iter()
+                .map(|p| Responder::new(p.id, &p.preferences, 1, num_proposers, num_responders))
+                .collect::<Result<_>>()?;
+
+            // Every Responder gets an entry, even if nobody is assigned to it
+            let mut assignment: HashMap<_, _> =
+                HashMap::from_iter(responders_input.iter().map(|r| (r.id, BTreeSet::new())));
+            for (p, accepted) in deferred_acceptance(proposers, responders)? {
+                for r in accepted.into_iter() {
+                    assignment.entry(r).or_insert_with(BTreeSet::new).insert(p);
+                }
+            }
+
+            Ok(assignment)
+        }
+    }
+}
+
+// The deferred acceptance loop from v0, where every Proposer keeps as many proposals out
+// as it has capacity for and Responders hold on to the best ones up to their capacity.
+// Proposers and Responders are looked up by id, so every id has to match its position
+pub fn deferred_acceptance(
+    mut proposers: Vec<Proposer>,
+    mut responders: Vec<Responder>,
+) -> Result<HashMap<ResponderId, BTreeSet<ProposerId>>> {
+    if let Some((index, p)) = proposers
+        .iter()
+        .enumerate()
+        .find(|(index, p)| p.id as usize != *index)
+    {
+        bail!("received proposer: {} at position: {}", p.id, index);
+    }
+
+    if let Some((index, r)) = responders
+        .iter()
+        .enumerate()
+        .find(|(index, r)| r.id as usize != *index)
+    {
+        bail!("received responder: {} at position: {}", r.id, index);
+    }
+
+    let mut unfilled: HashSet<_> = HashSet::from_iter(proposers.iter().map(|p| p.id));
+
+    while !unfilled.is_empty() {
+        // All Proposers with free slots propose to their highest ranked Responders
+        // that they have not proposed to yet. Proposers that have proposed to every
+        // Responder on their list keep whatever they have
+        for p in unfilled.iter() {
+            for preference in proposers[*p as usize].get_preferences() {
+                responders[preference as usize].add_proposal(*p);
+            }
+        }
+
+        unfilled = HashSet::new();
+
+        // All Responders keep their best proposals up to their capacity
+        // Any Proposers that have been rejected have a free slot again
+        for r in responders.iter_mut() {
+            for p in r.reject() {
+                proposers[p as usize].add_rejection()?;
+                unfilled.insert(p);
+            }
+        }
+    }
+
+    // Return a mapping from ResponderId : set(ProposerId)
+    Ok(HashMap::from_iter(
+        responders.into_iter().map(|r| (r.id, r.accepted)),
+    ))
+}
+
+// Every stable matching gives each Responder the same number of Proposers, so if the one
+// we find leaves some Responders under their lower quota, so does every other one
+pub fn stable_matching_with_lower_quotas(
+    proposers_input: &[ProposerInput],
+    responders_input: &[ResponderInput],
+    side: Side,
+) -> Result<HashMap<ResponderId, BTreeSet<ProposerId>>> {
+    validate_input(proposers_input, responders_input)?;
+
+    let assignment = stable_matching(proposers_input, responders_input, side)?;
+
+    let unmet: Vec<_> = responders_input
+        .iter()
+        .filter(|r| assignment.get(&r.id).map_or(0, |x| x.len()) < r.lower_quota)
+        .map(|r| r.id)
+        .collect();
+
+    if !unmet.is_empty() {
+        bail!(UnmetLowerQuotas { responders: unmet });
+    }
+
+    Ok(assignment)
+}
+
+// Close Responders that can't reach their lower quota until every open Responder does.
+// Closing a Responder only sends more Proposers to the others, so we close one at a
+// time, starting with the one furthest from its lower quota, and match everyone again
+pub fn stable_matching_closing_responders(
+    proposers_input: &[ProposerInput],
+    responders_input: &[ResponderInput],
+    side: Side,
+) -> Result<ClosedResponderMatching> {
+    validate_input(proposers_input, responders_input)?;
+
+    let mut closed: Vec<ResponderId> = Vec::new();
+
+    loop {
+        // Closed Responders don't accept anyone
+        let open: Vec<_> = responders_input
+            .iter()
+            .map(|r| {
+                if closed.contains(&r.id) {
+                    ResponderInput::with_quotas(r.id, Vec::new(), 0, 0)
+                } else {
+                    ResponderInput::with_quotas(
+                        r.id,
+                        r.preferences.clone(),
+                        r.lower_quota,
+                        r.capacity,
+                    )
+                }
+            })
+            .collect();
+
+        let mut assignment = stable_matching(proposers_input, &open, side)?;
+
+        let furthest = open
+            .iter()
+            .map(|r| {
+                let assigned = assignment.get(&r.id).map_or(0, |x| x.len());
+                (
+                    r.lower_quota.saturating_sub(assigned),
+                    std::cmp::Reverse(r.id),
+                )
+            })
+            .filter(|(missing, _)| *missing > 0)
+            .max();
+
+        match furthest {
+            Some((_, std::cmp::Reverse(r))) => closed.push(r),
+            None => {
+                for r in closed.iter() {
+                    assignment.remove(r);
+                }
+
+                return Ok(ClosedResponderMatching { assignment, closed });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{ResponderInput, Side, UnmetLowerQuotas};
+
+    #[test]
+    fn basic_hospitals_residents_test() {
+        let mut rng = rand::thread_rng();
+        for num_proposers in 1..40 {
+            for num_responders in 1..10 {
+                let (proposers, responders) = crate::input::random_many_to_one_input(
+                    num_proposers,
+                    num_responders,
+                    5,
+                    &mut rng,
+                );
+
+                for side in [Side::Proposer, Side::Responder].iter() {
+                    let assignment =
+                        super::stable_matching(&proposers, &responders, *side).unwrap();
+
+                    assert_eq!(
+                        crate::input::validate_many_to_one_matching(
+                            &proposers,
+                            &responders,
+                            &assignment
+                        ),
+                        true
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lower_quotas_test() {
+        let mut rng = rand::thread_rng();
+        for num_proposers in 1..30 {
+            for num_responders in 1..8 {
+                let (proposers, responders) = crate::input::random_lower_quota_input(
+                    num_proposers,
+                    num_responders,
+                    5,
+                    &mut rng,
+                );
+
+                for side in [Side::Proposer, Side::Responder].iter() {
+                    let assignment =
+                        super::stable_matching(&proposers, &responders, *side).unwrap();
+                    let unmet: Vec<_> = responders
+                        .iter()
+                        .filter(|r| assignment[&r.id].len() < r.lower_quota)
+                        .map(|r| r.id)
+                        .collect();
+
+                    match super::stable_matching_with_lower_quotas(&proposers, &responders, *side) {
+                        Ok(result) => {
+                            assert_eq!(unmet.is_empty(), true);
+                            assert_eq!(result, assignment);
+                        }
+                        Err(e) => {
+                            let e = e.downcast::<UnmetLowerQuotas>().unwrap();
+                            assert_eq!(e.responders, unmet);
+                        }
+                    }
+
+                    let result =
+                        super::stable_matching_closing_responders(&proposers, &responders, *side)
+                            .unwrap();
+                    assert_eq!(result.closed.is_empty(), unmet.is_empty());
+
+                    // The matching is stable once the closed Responders are taken out, and every
+                    // Responder that stayed open reaches its lower quota
+                    let open: Vec<_> = responders
+                        .iter()
+                        .map(|r| {
+                            if result.closed.contains(&r.id) {
+                                ResponderInput::with_capacity(r.id, Vec::new(), 0)
+                            } else {
+                                ResponderInput::with_capacity(
+                                    r.id,
+                                    r.preferences.clone(),
+                                    r.capacity,
+                                )
+                            }
+                        })
+                        .collect();
+                    assert_eq!(
+                        crate::input::validate_many_to_one_matching(
+                            &proposers,
+                            &open,
+                            &result.assignment
+                        ),
+                        true
+                    );
+
+                    for r in responders.iter().filter(|r| !result.closed.contains(&r.id)) {
+                        assert_eq!(result.assignment[&r.id].len() >= r.lower_quota, true);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_hospitals_residents_test() {
+        use crate::input::ProposerInput;
+
+        let valid = || {
+            (
+                vec![
+                    ProposerInput::new(0, vec![0, 1]),
+                    ProposerInput::new(1, vec![1, 0]),
+                    ProposerInput::new(2, vec![0]),
+                ],
+                vec![
+                    ResponderInput::with_quotas(0, vec![2, 1, 0], 1, 2),
+                    ResponderInput::with_quotas(1, vec![0, 1], 0, 1),
+                ],
+            )
+        };
+
+        let check = |proposers: &[ProposerInput], responders: &[ResponderInput], ok: bool| {
+            for side in [Side::Proposer, Side::Responder].iter() {
+                assert_eq!(
+                    super::stable_matching(proposers, responders, *side).is_ok(),
+                    ok
+                );
+                assert_eq!(
+                    super::stable_matching_with_lower_quotas(proposers, responders, *side).is_ok(),
+                    ok
+                );
+                assert_eq!(
+                    super::stable_matching_closing_responders(proposers, responders, *side).is_ok(),
+                    ok
+                );
+            }
+        };
+
+        let (proposers, responders) = valid();
+        check(&proposers, &responders, true);
+
+        // Ids have to be in range and match their position
+        let (mut proposers, responders) = valid();
+        proposers[2].id = 3;
+        check(&proposers, &responders, false);
+
+        let (mut proposers, responders) = valid();
+        proposers.swap(0, 1);
+        check(&proposers, &responders, false);
+
+        let (proposers, mut responders) = valid();
+        responders[1].id = 2;
+        check(&proposers, &responders, false);
+
+        // Preferences have to be in range, and list nobody twice
+        let (mut proposers, responders) = valid();
+        proposers[2].preferences.push(2);
+        check(&proposers, &responders, false);
+
+        let (proposers, mut responders) = valid();
+        responders[0].preferences = vec![2, 1, 2];
+        check(&proposers, &responders, false);
+    }
+
+    #[test]
+    fn duplicate_proposer_preferences_test() {
+        // Responders that propose list Proposers, and a Responder with room for two that
+        // listed the same Proposer twice would keep one of its slots forever
+        let preferences = vec![1, 0, 0];
+        assert_eq!(
+            super::Proposer::new(0, &preferences, 2, 1, 2).is_err(),
+            true
+        );
+
+        let preferences = vec![1, 0];
+        assert_eq!(super::Proposer::new(0, &preferences, 2, 1, 2).is_ok(), true);
+
+        let proposers = vec![
+            crate::input::ProposerInput::new(0, vec![0]),
+            crate::input::ProposerInput::new(1, vec![0]),
+        ];
+        let responders = vec![ResponderInput::with_capacity(0, vec![1, 0, 0], 2)];
+        assert_eq!(
+            super::stable_matching(&proposers, &responders, Side::Responder).is_err(),
+            true
+        );
+    }
+}
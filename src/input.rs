@@ -1,13 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::iter::{FromIterator, Iterator};
 use std::slice::Iter;
 
-use anyhow::Result;
-use rand::rngs::ThreadRng;
+use anyhow::{bail, Result};
+use rand::rngs::{StdRng, ThreadRng};
 use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 
 pub type ProposerId = u32;
 pub type ResponderId = u32;
+pub type RoommateId = u32;
+pub type StudentId = u32;
+pub type ProjectId = u32;
+pub type LecturerId = u32;
 
 #[derive(Debug)]
 pub struct ProposerInput {
@@ -19,6 +24,10 @@ pub struct ProposerInput {
 pub struct ResponderInput {
     pub id: ResponderId,
     pub preferences: Vec<ProposerId>,
+    // Minimum number of Proposers this Responder needs to be matched with to stay open
+    pub lower_quota: usize,
+    // Maximum number of Proposers this Responder can be matched with
+    pub capacity: usize,
 }
 
 impl ProposerInput {
@@ -29,8 +38,442 @@ impl ProposerInput {
 
 impl ResponderInput {
     pub fn new(id: ResponderId, preferences: Vec<ProposerId>) -> Self {
-        ResponderInput { id, preferences }
+        ResponderInput::with_capacity(id, preferences, 1)
     }
+
+    pub fn with_capacity(id: ResponderId, preferences: Vec<ProposerId>, capacity: usize) -> Self {
+        ResponderInput::with_quotas(id, preferences, 0, capacity)
+    }
+
+    pub fn with_quotas(
+        id: ResponderId,
+        preferences: Vec<ProposerId>,
+        lower_quota: usize,
+        capacity: usize,
+    ) -> Self {
+        ResponderInput {
+            id,
+            preferences,
+            lower_quota,
+            capacity,
+        }
+    }
+
+    // Whether this Responder would take every Proposer in incoming on top of the ones it
+    // is already assigned, possibly by dropping its least preferred assignments
+    pub fn would_accept(&self, assigned: &[ProposerId], incoming: &[ProposerId]) -> bool {
+        if !incoming.iter().all(|p| self.accepts(*p)) {
+            return false;
+        }
+
+        // Everyone incoming has to be among the Proposers this Responder likes the most
+        let mut candidates: Vec<_> = assigned.iter().chain(incoming.iter()).cloned().collect();
+        candidates.sort_by_key(|p| std::cmp::Reverse(self.preferences.iter().position(|x| x == p)));
+        candidates.truncate(self.capacity);
+
+        incoming.iter().all(|p| candidates.contains(p))
+    }
+}
+
+// Proposers in the many-to-many problem, which can be matched with several Responders
+#[derive(Debug)]
+pub struct ManyToManyProposerInput {
+    pub id: ProposerId,
+    pub preferences: Vec<ResponderId>,
+    // Maximum number of Responders this Proposer can be matched with
+    pub capacity: usize,
+}
+
+impl ManyToManyProposerInput {
+    pub fn new(id: ProposerId, preferences: Vec<ResponderId>, capacity: usize) -> Self {
+        ManyToManyProposerInput {
+            id,
+            preferences,
+            capacity,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CoupleInput {
+    // Proposers that have to be matched together
+    pub members: (ProposerId, ProposerId),
+    // List of (Responder for the first member, Responder for the second member) pairs
+    // ordered by ascending preference
+    pub preferences: Vec<(ResponderId, ResponderId)>,
+}
+
+impl CoupleInput {
+    pub fn new(
+        members: (ProposerId, ProposerId),
+        preferences: Vec<(ResponderId, ResponderId)>,
+    ) -> Self {
+        CoupleInput {
+            members,
+            preferences,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RoommateInput {
+    pub id: RoommateId,
+    // List of other Roommates ordered by ascending preference
+    pub preferences: Vec<RoommateId>,
+}
+
+impl RoommateInput {
+    pub fn new(id: RoommateId, preferences: Vec<RoommateId>) -> Self {
+        RoommateInput { id, preferences }
+    }
+}
+
+#[derive(Debug)]
+pub struct StudentInput {
+    pub id: StudentId,
+    // List of Projects ordered by ascending preference
+    pub preferences: Vec<ProjectId>,
+}
+
+#[derive(Debug)]
+pub struct ProjectInput {
+    pub id: ProjectId,
+    // The Lecturer offering this Project
+    pub lecturer: LecturerId,
+    // Maximum number of Students that can be assigned to this Project
+    pub capacity: usize,
+}
+
+#[derive(Debug)]
+pub struct LecturerInput {
+    pub id: LecturerId,
+    // List of Students ordered by ascending preference. Every Project a Lecturer offers
+    // ranks the Students that find it acceptable in the same order
+    pub preferences: Vec<StudentId>,
+    // Maximum number of Students that can be assigned across all of this Lecturer's
+    // Projects
+    pub capacity: usize,
+}
+
+impl StudentInput {
+    pub fn new(id: StudentId, preferences: Vec<ProjectId>) -> Self {
+        StudentInput { id, preferences }
+    }
+}
+
+impl ProjectInput {
+    pub fn new(id: ProjectId, lecturer: LecturerId, capacity: usize) -> Self {
+        ProjectInput {
+            id,
+            lecturer,
+            capacity,
+        }
+    }
+}
+
+impl LecturerInput {
+    pub fn new(id: LecturerId, preferences: Vec<StudentId>, capacity: usize) -> Self {
+        LecturerInput {
+            id,
+            preferences,
+            capacity,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TiedProposerInput {
+    pub id: ProposerId,
+    // Indifference classes of Responders ordered by ascending preference
+    pub preferences: Vec<Vec<ResponderId>>,
+}
+
+#[derive(Debug)]
+pub struct TiedResponderInput {
+    pub id: ResponderId,
+    // Indifference classes of Proposers ordered by ascending preference
+    pub preferences: Vec<Vec<ProposerId>>,
+}
+
+impl TiedProposerInput {
+    pub fn new(id: ProposerId, preferences: Vec<Vec<ResponderId>>) -> Self {
+        TiedProposerInput { id, preferences }
+    }
+}
+
+impl TiedResponderInput {
+    pub fn new(id: ResponderId, preferences: Vec<Vec<ProposerId>>) -> Self {
+        TiedResponderInput { id, preferences }
+    }
+}
+
+// How to order agents that share an indifference class when converting tied
+// preferences into the strict preferences every engine expects
+#[derive(Debug, Clone)]
+pub enum TieBreak {
+    // Agents with smaller ids are preferred
+    Deterministic,
+    // Every indifference class is shuffled by a random number generator seeded
+    // with the given seed
+    Random(u64),
+    // Agents are ordered by their position in a master list of each side, which
+    // like every other preference list is ordered by ascending preference
+    MasterList {
+        proposers: Vec<ProposerId>,
+        responders: Vec<ResponderId>,
+    },
+}
+
+// Something that blocks a matching with couples
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CouplesBlockingPair {
+    // A single Proposer and a Responder that would rather be matched together
+    Single {
+        proposer: ProposerId,
+        responder: ResponderId,
+    },
+    // A couple and a pair of Responders that would take them
+    Couple {
+        members: (ProposerId, ProposerId),
+        responders: (ResponderId, ResponderId),
+    },
+}
+
+// Pairs that have to be, or can never be, matched together. Forbidden pairs still
+// count when checking if a matching is stable
+#[derive(Debug, Clone, Default)]
+pub struct Constraints {
+    pub forced: Vec<(ProposerId, ResponderId)>,
+    pub forbidden: Vec<(ProposerId, ResponderId)>,
+}
+
+// The constraint that keeps every stable matching from meeting the rest of them
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Infeasible {
+    Forced(ProposerId, ResponderId),
+    Forbidden(ProposerId, ResponderId),
+}
+
+impl std::fmt::Display for Infeasible {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Infeasible::Forced(p, r) => write!(
+                f,
+                "no stable matching meeting the constraints matches proposer: {} with responder: {}",
+                p, r
+            ),
+            Infeasible::Forbidden(p, r) => write!(
+                f,
+                "proposer: {} and responder: {} block every stable matching that keeps them apart",
+                p, r
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Infeasible {}
+
+// Responders that end up under their lower quota in every stable matching
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UnmetLowerQuotas {
+    pub responders: Vec<ResponderId>,
+}
+
+impl std::fmt::Display for UnmetLowerQuotas {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "no stable matching meets the lower quotas of responders: {:?}",
+            self.responders
+        )
+    }
+}
+
+impl std::error::Error for UnmetLowerQuotas {}
+
+// Proposers that all need one of fewer Responders to be matched in a popular matching
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NoPopularMatching {
+    pub proposers: Vec<ProposerId>,
+    pub responders: Vec<ResponderId>,
+}
+
+impl std::fmt::Display for NoPopularMatching {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "no popular matching exists: proposers: {:?} compete for responders: {:?}",
+            self.proposers, self.responders
+        )
+    }
+}
+
+impl std::error::Error for NoPopularMatching {}
+
+// Notions of stability for preference lists with ties, from weakest to strongest
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Stability {
+    Weak,
+    Strong,
+    Super,
+}
+
+// Which side of the market makes the proposals
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Side {
+    Proposer,
+    Responder,
+}
+
+#[derive(Debug)]
+pub struct MatchingResult {
+    pub matching: HashMap<ProposerId, ResponderId>,
+    pub unmatched_proposers: BTreeSet<ProposerId>,
+    pub unmatched_responders: BTreeSet<ResponderId>,
+}
+
+impl MatchingResult {
+    pub fn new(
+        proposers: &[ProposerInput],
+        responders: &[ResponderInput],
+        matching: HashMap<ProposerId, ResponderId>,
+    ) -> Self {
+        let matched_responders: BTreeSet<_> = matching.values().cloned().collect();
+
+        MatchingResult {
+            unmatched_proposers: proposers
+                .iter()
+                .map(|p| p.id)
+                .filter(|p| !matching.contains_key(p))
+                .collect(),
+            unmatched_responders: responders
+                .iter()
+                .map(|r| r.id)
+                .filter(|r| !matched_responders.contains(r))
+                .collect(),
+            matching,
+        }
+    }
+
+    // Turn a result computed with the sides swapped back into one keyed by ProposerId
+    pub fn swap_sides(self) -> Self {
+        MatchingResult {
+            matching: self.matching.into_iter().map(|(r, p)| (p, r)).collect(),
+            unmatched_proposers: self.unmatched_responders,
+            unmatched_responders: self.unmatched_proposers,
+        }
+    }
+}
+
+impl Constraints {
+    pub fn new(
+        forced: Vec<(ProposerId, ResponderId)>,
+        forbidden: Vec<(ProposerId, ResponderId)>,
+    ) -> Self {
+        Constraints { forced, forbidden }
+    }
+
+    // Turn constraints on (ProposerId, ResponderId) pairs into ones on the instance with
+    // the sides swapped
+    pub fn swap_sides(&self) -> Self {
+        let swap = |pairs: &[(u32, u32)]| pairs.iter().map(|(p, r)| (*r, *p)).collect();
+        Constraints::new(swap(&self.forced), swap(&self.forbidden))
+    }
+
+    // Every pair that can't be part of the matching. Forcing a pair rules out every other
+    // pair either of its members could be in
+    pub fn excluded_pairs(
+        &self,
+        proposers: &[ProposerInput],
+        responders: &[ResponderInput],
+    ) -> HashSet<(ProposerId, ResponderId)> {
+        let mut excluded: HashSet<_> = self.forbidden.iter().cloned().collect();
+
+        for (p, r) in self.forced.iter() {
+            if let Some(proposer) = proposers.iter().find(|x| x.id == *p) {
+                excluded.extend(proposer.preferences.iter().map(|x| (*p, *x)));
+            }
+            if let Some(responder) = responders.iter().find(|x| x.id == *r) {
+                excluded.extend(responder.preferences.iter().map(|x| (*x, *r)));
+            }
+        }
+
+        for pair in self.forced.iter() {
+            if !self.forbidden.contains(pair) {
+                excluded.remove(pair);
+            }
+        }
+
+        excluded
+    }
+
+    // Find the constraint to blame when a matching misses a forced pair, or when one of
+    // the excluded pairs blocks it
+    pub fn violation(
+        &self,
+        proposers: &[ProposerInput],
+        responders: &[ResponderInput],
+        matching: &HashMap<ProposerId, ResponderId>,
+    ) -> Option<Infeasible> {
+        let reverse: HashMap<_, _> = HashMap::from_iter(matching.iter().map(|(p, r)| (*r, *p)));
+
+        // Being unmatched is worse than being matched with anyone acceptable
+        let blocks = |p: ProposerId, r: ResponderId| {
+            let proposer = proposers.iter().find(|x| x.id == p);
+            let responder = responders.iter().find(|x| x.id == r);
+
+            match (proposer, responder) {
+                (Some(proposer), Some(responder)) => {
+                    let proposer_prefers_more = match matching.get(&p) {
+                        Some(assigned) => proposer.prefers_more(*assigned, r),
+                        None => proposer.accepts(r),
+                    };
+                    let responder_prefers_more = match reverse.get(&r) {
+                        Some(assigned) => responder.prefers_more(*assigned, p),
+                        None => responder.accepts(p),
+                    };
+                    proposer_prefers_more && responder_prefers_more
+                }
+                _ => false,
+            }
+        };
+
+        for (p, r) in self.forced.iter() {
+            if matching.get(p) != Some(r) {
+                return Some(Infeasible::Forced(*p, *r));
+            }
+        }
+
+        for (p, r) in self.forbidden.iter() {
+            if blocks(*p, *r) {
+                return Some(Infeasible::Forbidden(*p, *r));
+            }
+        }
+
+        // Pairs ruled out by forcing another pair can block as well
+        let excluded = self.excluded_pairs(proposers, responders);
+        self.forced
+            .iter()
+            .find(|(p, r)| {
+                excluded
+                    .iter()
+                    .any(|(x, y)| (x == p || y == r) && blocks(*x, *y))
+            })
+            .map(|(p, r)| Infeasible::Forced(*p, *r))
+    }
+}
+
+#[derive(Debug)]
+pub struct ExtremalMatchings {
+    // Every Proposer likes their partner here at least as much as in any other
+    // stable matching, and every Responder likes theirs at most as much
+    pub proposer_optimal: HashMap<ProposerId, ResponderId>,
+    // And vice versa
+    pub responder_optimal: HashMap<ProposerId, ResponderId>,
+    // Agents whose partner differs between the two. Everyone else has the same
+    // partner (or lack of one) in every stable matching
+    pub differing_proposers: BTreeSet<ProposerId>,
+    pub differing_responders: BTreeSet<ResponderId>,
 }
 
 pub trait MatchingInput {
@@ -38,6 +481,10 @@ pub trait MatchingInput {
 
     fn preferences(&self) -> Iter<u32>;
 
+    fn accepts(&self, other: u32) -> bool {
+        self.preferences().any(|x| *x == other)
+    }
+
     fn prefers_more(&self, assigned: u32, alternative: u32) -> bool {
         let mut assigned_pref = None;
         let mut alternative_pref = None;
@@ -79,29 +526,246 @@ impl MatchingInput for ResponderInput {
     }
 }
 
-pub fn validate_matching(
+impl MatchingInput for ManyToManyProposerInput {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn preferences(&self) -> Iter<u32> {
+        self.preferences.iter()
+    }
+}
+
+impl MatchingInput for RoommateInput {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn preferences(&self) -> Iter<u32> {
+        self.preferences.iter()
+    }
+}
+
+pub trait TiedMatchingInput {
+    fn id(&self) -> u32;
+
+    fn preferences(&self) -> Iter<Vec<u32>>;
+
+    // Index of the indifference class containing other, or None if other is not acceptable
+    fn tier(&self, other: u32) -> Option<usize> {
+        self.preferences()
+            .position(|tier| tier.iter().any(|x| *x == other))
+    }
+
+    // Being unassigned is worse than being assigned anyone acceptable
+    fn prefers_more(&self, assigned: Option<u32>, alternative: u32) -> bool {
+        match (assigned.and_then(|a| self.tier(a)), self.tier(alternative)) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(assigned), Some(alternative)) => alternative > assigned,
+        }
+    }
+
+    fn likes_at_least(&self, assigned: Option<u32>, alternative: u32) -> bool {
+        match (assigned.and_then(|a| self.tier(a)), self.tier(alternative)) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(assigned), Some(alternative)) => alternative >= assigned,
+        }
+    }
+}
+
+impl TiedMatchingInput for TiedProposerInput {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn preferences(&self) -> Iter<Vec<u32>> {
+        self.preferences.iter()
+    }
+}
+
+impl TiedMatchingInput for TiedResponderInput {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn preferences(&self) -> Iter<Vec<u32>> {
+        self.preferences.iter()
+    }
+}
+
+// Anything that ranks the other side, where agents in the same indifference class share
+// a rank. Strict preference lists give every agent a rank of its own
+pub trait Ranked {
+    fn agent_id(&self) -> u32;
+
+    // Higher ranks are preferred, and None means other is not acceptable
+    fn rank(&self, other: u32) -> Option<usize>;
+
+    // Being unassigned is worse than being assigned anyone acceptable
+    fn strictly_prefers(&self, assigned: Option<u32>, alternative: u32) -> bool {
+        match (assigned.and_then(|a| self.rank(a)), self.rank(alternative)) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(assigned), Some(alternative)) => alternative > assigned,
+        }
+    }
+}
+
+impl Ranked for ProposerInput {
+    fn agent_id(&self) -> u32 {
+        self.id
+    }
+
+    fn rank(&self, other: u32) -> Option<usize> {
+        self.preferences.iter().position(|x| *x == other)
+    }
+}
+
+impl Ranked for ResponderInput {
+    fn agent_id(&self) -> u32 {
+        self.id
+    }
+
+    fn rank(&self, other: u32) -> Option<usize> {
+        self.preferences.iter().position(|x| *x == other)
+    }
+}
+
+impl Ranked for TiedProposerInput {
+    fn agent_id(&self) -> u32 {
+        self.id
+    }
+
+    fn rank(&self, other: u32) -> Option<usize> {
+        self.tier(other)
+    }
+}
+
+impl Ranked for TiedResponderInput {
+    fn agent_id(&self) -> u32 {
+        self.id
+    }
+
+    fn rank(&self, other: u32) -> Option<usize> {
+        self.tier(other)
+    }
+}
+
+// Works with the one-to-one input and with many-to-many Proposers
+pub fn validate_input<P: MatchingInput, R: MatchingInput>(
+    proposers: &[P],
+    responders: &[R],
+) -> Result<()> {
+    // Every agent's id is its position on its side, so ids live in [0, n) where n is the
+    // size of that side. Every preference has to refer to an agent on the other side, at
+    // most once
+    let has_duplicates = |preferences: Iter<u32>| {
+        let len = preferences.len();
+        preferences.collect::<HashSet<_>>().len() != len
+    };
+
+    for (index, p) in proposers.iter().enumerate() {
+        if p.id() as usize != index {
+            bail!("received invalid proposer input id: {}", p.id());
+        }
+
+        if p.preferences().any(|r| *r as usize >= responders.len()) {
+            bail!(
+                "received invalid preferences in proposer: {} preferences: {:?}",
+                p.id(),
+                p.preferences().collect::<Vec<_>>()
+            );
+        }
+
+        if has_duplicates(p.preferences()) {
+            bail!(
+                "received duplicate preferences in proposer: {} preferences: {:?}",
+                p.id(),
+                p.preferences().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    for (index, r) in responders.iter().enumerate() {
+        if r.id() as usize != index {
+            bail!("received invalid responder input id: {}", r.id());
+        }
+
+        if r.preferences().any(|p| *p as usize >= proposers.len()) {
+            bail!(
+                "received invalid preferences in responder: {} preferences: {:?}",
+                r.id(),
+                r.preferences().collect::<Vec<_>>()
+            );
+        }
+
+        if has_duplicates(r.preferences()) {
+            bail!(
+                "received duplicate preferences in responder: {} preferences: {:?}",
+                r.id(),
+                r.preferences().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// The one-to-one engines match every Responder with at most one Proposer and have no
+// notion of lower quotas, so we reject anything else rather than ignoring it
+pub fn validate_one_to_one_input(
     proposers: &[ProposerInput],
     responders: &[ResponderInput],
+) -> Result<()> {
+    validate_input(proposers, responders)?;
+
+    if let Some(r) = responders
+        .iter()
+        .find(|r| r.capacity != 1 || r.lower_quota != 0)
+    {
+        bail!(
+            "received one-to-one input with responder: {} lower quota: {} capacity: {}",
+            r.id,
+            r.lower_quota,
+            r.capacity
+        );
+    }
+
+    Ok(())
+}
+
+// Works with strict preference lists and with ties. With ties only pairs that strictly
+// prefer each other block the matching, so this checks weak stability
+pub fn validate_matching<P: Ranked, R: Ranked>(
+    proposers: &[P],
+    responders: &[R],
     matching: &HashMap<u32, u32>,
 ) -> bool {
-    let reverse: HashMap<_, _> = HashMap::from_iter(matching.iter().map(|(p, r)| (r, p)));
+    let reverse: HashMap<_, _> = HashMap::from_iter(matching.iter().map(|(p, r)| (*r, *p)));
+
+    if reverse.len() != matching.len() {
+        // Some responder was matched with more than one proposer
+        return false;
+    }
 
     for p in proposers.iter() {
         for r in responders.iter() {
-            let proposer_match = matching.get(&p.id());
-            let responder_match = reverse.get(&r.id());
+            let proposer_match = matching.get(&p.agent_id()).cloned();
+            let responder_match = reverse.get(&r.agent_id()).cloned();
 
-            if proposer_match.is_none() || responder_match.is_none() {
-                // This matching has to be invalid because every proposer and responder should have
-                // been matched.
-                return false;
-            }
+            if proposer_match == Some(r.agent_id()) {
+                if p.rank(r.agent_id()).is_none() || r.rank(p.agent_id()).is_none() {
+                    // Only mutually acceptable pairs can be matched
+                    return false;
+                }
 
-            let proposer_match = proposer_match.expect("proposer match known to exist");
-            let responder_match = responder_match.expect("responder match known to exist");
+                continue;
+            }
 
-            let proposer_prefers_more = p.prefers_more(*proposer_match, r.id());
-            let responder_prefers_more = r.prefers_more(**responder_match, p.id());
+            let proposer_prefers_more = p.strictly_prefers(proposer_match, r.agent_id());
+            let responder_prefers_more = r.strictly_prefers(responder_match, p.agent_id());
 
             if proposer_prefers_more && responder_prefers_more {
                 // Both the proposer p and the responder r mutually prefer each other over their
@@ -114,35 +778,1345 @@ pub fn validate_matching(
     return true;
 }
 
-pub fn random_input(n: u32, rng: &mut ThreadRng) -> (Vec<ProposerInput>, Vec<ResponderInput>) {
-    let mut proposers = Vec::with_capacity(n as usize);
-    let mut responders = Vec::with_capacity(n as usize);
+pub fn validate_roommates_matching(
+    roommates: &[RoommateInput],
+    matching: &HashMap<RoommateId, RoommateId>,
+) -> bool {
+    for (a, b) in matching.iter() {
+        if matching.get(b) != Some(a) || a == b {
+            // Every pair has to show up in both directions
+            return false;
+        }
 
-    let preferences: Vec<u32> = (0..n).collect();
+        let acceptable = match (
+            roommates.iter().find(|x| x.id == *a),
+            roommates.iter().find(|x| x.id == *b),
+        ) {
+            (Some(x), Some(y)) => x.accepts(*b) && y.accepts(*a),
+            _ => false,
+        };
 
-    for i in 0..n {
-        let mut proposer_pref = preferences.clone();
-        proposer_pref.shuffle(rng);
+        if !acceptable {
+            // Only mutually acceptable pairs can be matched
+            return false;
+        }
+    }
 
-        proposers.push(ProposerInput::new(i, proposer_pref));
+    for a in roommates.iter() {
+        for b in roommates.iter() {
+            if a.id() == b.id() || matching.get(&a.id()) == Some(&b.id()) {
+                continue;
+            }
 
-        let mut responder_pref = preferences.clone();
-        responder_pref.shuffle(rng);
+            // Being unmatched is worse than being matched with anyone acceptable
+            let a_prefers_more = match matching.get(&a.id()) {
+                Some(a_match) => a.prefers_more(*a_match, b.id()),
+                None => a.accepts(b.id()),
+            };
+            let b_prefers_more = match matching.get(&b.id()) {
+                Some(b_match) => b.prefers_more(*b_match, a.id()),
+                None => b.accepts(a.id()),
+            };
+
+            if a_prefers_more && b_prefers_more {
+                // a and b would both rather be with each other
+                return false;
+            }
+        }
+    }
+
+    return true;
+}
+
+// A stable partition is given by its cycles, where every Roommate in a cycle is followed
+// by its successor. Pairs are cycles of length two and unmatched Roommates are cycles of
+// length one
+pub fn validate_stable_partition(roommates: &[RoommateInput], cycles: &[Vec<RoommateId>]) -> bool {
+    let mut successor: HashMap<RoommateId, RoommateId> = HashMap::new();
+    let mut predecessor: HashMap<RoommateId, RoommateId> = HashMap::new();
+
+    for cycle in cycles.iter() {
+        for (i, a) in cycle.iter().enumerate() {
+            let b = cycle[(i + 1) % cycle.len()];
+            if successor.insert(*a, b).is_some() || predecessor.insert(b, *a).is_some() {
+                // Every Roommate has to show up in exactly one cycle
+                return false;
+            }
+        }
+    }
+
+    for a in roommates.iter() {
+        let (succ, pred) = match (successor.get(&a.id()), predecessor.get(&a.id())) {
+            (Some(succ), Some(pred)) => (*succ, *pred),
+            _ => return false,
+        };
+
+        if succ == a.id() {
+            continue;
+        }
+
+        let acceptable = match roommates.iter().find(|x| x.id == succ) {
+            Some(b) => a.accepts(succ) && b.accepts(a.id()),
+            None => false,
+        };
+
+        if !acceptable || (succ != pred && !a.prefers_more(pred, succ)) {
+            // Neighbours in a cycle have to be mutually acceptable, and everyone has to
+            // prefer their successor to their predecessor
+            return false;
+        }
+    }
+
+    for a in roommates.iter() {
+        for b in roommates.iter() {
+            if a.id() == b.id() {
+                continue;
+            }
+
+            // Roommates in cycles of length one only have themselves to fall back on
+            let a_pred = predecessor[&a.id()];
+            let b_pred = predecessor[&b.id()];
+            let a_prefers_more = if a_pred == a.id() {
+                a.accepts(b.id())
+            } else {
+                a.prefers_more(a_pred, b.id())
+            };
+            let b_prefers_more = if b_pred == b.id() {
+                b.accepts(a.id())
+            } else {
+                b.prefers_more(b_pred, a.id())
+            };
+
+            if a_prefers_more && b_prefers_more {
+                // a and b both prefer each other over their predecessors
+                return false;
+            }
+        }
+    }
+
+    return true;
+}
+
+pub fn validate_many_to_one_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    assignment: &HashMap<ResponderId, BTreeSet<ProposerId>>,
+) -> bool {
+    let mut matching: HashMap<ProposerId, ResponderId> = HashMap::new();
+
+    for r in responders.iter() {
+        let assigned = match assignment.get(&r.id()) {
+            Some(assigned) => assigned,
+            None => continue,
+        };
+
+        if assigned.len() > r.capacity {
+            // This responder was assigned more proposers than it can take
+            return false;
+        }
+
+        for p in assigned.iter() {
+            if !r.accepts(*p) {
+                // Responders can only be assigned proposers they find acceptable
+                return false;
+            }
+
+            if matching.insert(*p, r.id()).is_some() {
+                // Every proposer can be assigned to at most one responder
+                return false;
+            }
+        }
+    }
+
+    for p in proposers.iter() {
+        if let Some(proposer_match) = matching.get(&p.id()) {
+            if !p.accepts(*proposer_match) {
+                // Proposers can only be assigned responders they find acceptable
+                return false;
+            }
+        }
+
+        for r in responders.iter() {
+            let proposer_match = matching.get(&p.id());
+            let assigned = assignment.get(&r.id());
+
+            if proposer_match == Some(&r.id()) {
+                continue;
+            }
+
+            let proposer_prefers_more = match proposer_match {
+                Some(proposer_match) => p.prefers_more(*proposer_match, r.id()),
+                None => p.accepts(r.id()),
+            };
+
+            let responder_prefers_more = match assigned {
+                Some(assigned) if assigned.len() >= r.capacity => {
+                    assigned.iter().any(|q| r.prefers_more(*q, p.id()))
+                }
+                _ => r.accepts(p.id()),
+            };
+
+            if proposer_prefers_more && responder_prefers_more {
+                // The proposer p would rather be with r, and r either has a free slot or would
+                // rather take p than one of its current assignments
+                return false;
+            }
+        }
+    }
+
+    return true;
+}
+
+// Whether no other matching makes some Proposer better off without making another worse
+// off, where every Responder takes at most its capacity and only mutually acceptable pairs
+// are matched. Any improvement moves Proposers along cycles of Responders, or along a
+// path that ends at a Responder with a free slot, so we look for either of those
+pub fn is_pareto_efficient(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    matching: &HashMap<ProposerId, ResponderId>,
+) -> bool {
+    // Mapping from ResponderId -> the Responders its Proposers would rather have
+    let mut improvements: HashMap<ResponderId, BTreeSet<ResponderId>> = HashMap::new();
+
+    for p in proposers.iter() {
+        let assigned = matching.get(&p.id);
+
+        for r in responders.iter() {
+            let prefers_more = match assigned {
+                Some(assigned) => p.prefers_more(*assigned, r.id),
+                None => p.accepts(r.id),
+            };
+
+            if !prefers_more || !r.accepts(p.id) {
+                continue;
+            }
+
+            if matching.values().filter(|x| **x == r.id).count() < r.capacity {
+                // p can just take a free slot at r
+                return false;
+            }
+
+            if let Some(assigned) = assigned {
+                improvements
+                    .entry(*assigned)
+                    .or_insert_with(BTreeSet::new)
+                    .insert(r.id);
+            }
+        }
+    }
+
+    // Look for a cycle with a depth first search, where a Responder that is still on the
+    // stack when we see it again closes a cycle
+    fn has_cycle(
+        r: ResponderId,
+        improvements: &HashMap<ResponderId, BTreeSet<ResponderId>>,
+        on_stack: &mut BTreeSet<ResponderId>,
+        done: &mut BTreeSet<ResponderId>,
+    ) -> bool {
+        if on_stack.contains(&r) {
+            return true;
+        }
+        if done.contains(&r) {
+            return false;
+        }
+
+        on_stack.insert(r);
+        let found = improvements.get(&r).map_or(false, |next| {
+            next.iter()
+                .any(|x| has_cycle(*x, improvements, on_stack, done))
+        });
+        on_stack.remove(&r);
+        done.insert(r);
+
+        found
+    }
+
+    let mut done = BTreeSet::new();
+    !improvements
+        .keys()
+        .any(|r| has_cycle(*r, &improvements, &mut BTreeSet::new(), &mut done))
+}
+
+// Number of (Proposer, Responder) pairs where the Proposer would rather have the
+// Responder, and the Responder gives it a higher priority than one of the Proposers it
+// was assigned
+pub fn justified_envy_count(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    matching: &HashMap<ProposerId, ResponderId>,
+) -> usize {
+    let mut count = 0;
+
+    for p in proposers.iter() {
+        for r in responders.iter() {
+            let prefers_more = match matching.get(&p.id) {
+                Some(assigned) => p.prefers_more(*assigned, r.id),
+                None => p.accepts(r.id),
+            };
+
+            let envied = matching
+                .iter()
+                .any(|(q, x)| *x == r.id && r.accepts(p.id) && r.prefers_more(*q, p.id));
+
+            if prefers_more && envied {
+                count += 1;
+            }
+        }
+    }
+
+    count
+}
+
+pub fn validate_many_to_many_matching(
+    proposers: &[ManyToManyProposerInput],
+    responders: &[ResponderInput],
+    assignment: &HashMap<ProposerId, BTreeSet<ResponderId>>,
+) -> bool {
+    let mut reverse: HashMap<ResponderId, BTreeSet<ProposerId>> = HashMap::new();
+
+    for (p, assigned) in assignment.iter() {
+        let proposer = match proposers.iter().find(|x| x.id() == *p) {
+            Some(proposer) => proposer,
+            None => return false,
+        };
+
+        if assigned.len() > proposer.capacity || assigned.iter().any(|r| !proposer.accepts(*r)) {
+            // Proposers take at most their capacity, and only responders they find acceptable
+            return false;
+        }
+
+        for r in assigned.iter() {
+            reverse.entry(*r).or_insert_with(BTreeSet::new).insert(*p);
+        }
+    }
+
+    for (r, assigned) in reverse.iter() {
+        let responder = match responders.iter().find(|x| x.id() == *r) {
+            Some(responder) => responder,
+            None => return false,
+        };
+
+        if assigned.len() > responder.capacity || assigned.iter().any(|p| !responder.accepts(*p)) {
+            // And the same goes for responders
+            return false;
+        }
+    }
+
+    for p in proposers.iter() {
+        for r in responders.iter() {
+            let proposer_assigned = assignment.get(&p.id());
+            let responder_assigned = reverse.get(&r.id());
+
+            if proposer_assigned.map_or(false, |assigned| assigned.contains(&r.id())) {
+                continue;
+            }
+
+            // Either side wants the pair if it has a free slot for an acceptable partner, or
+            // would rather have it than one of its current partners
+            let proposer_prefers_more = match proposer_assigned {
+                Some(assigned) if assigned.len() >= p.capacity => {
+                    assigned.iter().any(|x| p.prefers_more(*x, r.id()))
+                }
+                _ => p.accepts(r.id()),
+            };
+
+            let responder_prefers_more = match responder_assigned {
+                Some(assigned) if assigned.len() >= r.capacity => {
+                    assigned.iter().any(|x| r.prefers_more(*x, p.id()))
+                }
+                _ => r.accepts(p.id()),
+            };
+
+            if proposer_prefers_more && responder_prefers_more {
+                // The pair (p, r) blocks the matching
+                return false;
+            }
+        }
+    }
+
+    return true;
+}
+
+// Checks that a matching from Students to Projects respects every capacity and only
+// uses acceptable pairs, and that no Student and Project block it. A Student s that
+// would rather have a Project p offered by Lecturer l blocks the matching when
+//   1. p and l both have room, or
+//   2. p has room, l is full, and either s is already one of l's Students or l
+//      prefers s to their worst Student, or
+//   3. p is full and l prefers s to the worst Student assigned to p
+pub fn validate_spa_matching(
+    students: &[StudentInput],
+    projects: &[ProjectInput],
+    lecturers: &[LecturerInput],
+    matching: &HashMap<StudentId, ProjectId>,
+) -> bool {
+    let position = |l: &LecturerInput, s: StudentId| l.preferences.iter().position(|x| *x == s);
+
+    let mut project_students: HashMap<ProjectId, Vec<StudentId>> = HashMap::new();
+    let mut lecturer_students: HashMap<LecturerId, Vec<StudentId>> = HashMap::new();
+
+    for (s, p) in matching.iter() {
+        let student = students.iter().find(|x| x.id == *s);
+        let project = projects.iter().find(|x| x.id == *p);
+        let lecturer = project.and_then(|p| lecturers.iter().find(|x| x.id == p.lecturer));
+
+        match (student, lecturer) {
+            (Some(student), Some(lecturer))
+                if student.preferences.contains(p) && position(lecturer, *s).is_some() =>
+            {
+                project_students.entry(*p).or_insert_with(Vec::new).push(*s);
+                lecturer_students
+                    .entry(lecturer.id)
+                    .or_insert_with(Vec::new)
+                    .push(*s);
+            }
+            // Only mutually acceptable pairs can be matched
+            _ => return false,
+        }
+    }
+
+    for p in projects.iter() {
+        if project_students.get(&p.id).map_or(0, |x| x.len()) > p.capacity {
+            return false;
+        }
+    }
+
+    for l in lecturers.iter() {
+        if lecturer_students.get(&l.id).map_or(0, |x| x.len()) > l.capacity {
+            return false;
+        }
+    }
+
+    for s in students.iter() {
+        // Only Projects s likes more than its assignment can block
+        let start = match matching.get(&s.id) {
+            Some(p) => match s.preferences.iter().position(|x| x == p) {
+                Some(index) => index + 1,
+                None => 0,
+            },
+            None => 0,
+        };
+
+        for p in s.preferences[start..].iter() {
+            let project = match projects.iter().find(|x| x.id == *p) {
+                Some(project) => project,
+                None => return false,
+            };
+            let lecturer = match lecturers.iter().find(|x| x.id == project.lecturer) {
+                Some(lecturer) => lecturer,
+                None => return false,
+            };
+
+            let rank = match position(lecturer, s.id) {
+                Some(rank) => rank,
+                // The Lecturer does not find s acceptable
+                None => continue,
+            };
+            let worst = |assigned: Option<&Vec<StudentId>>| {
+                assigned
+                    .map(|x| x.iter().filter_map(|s| position(lecturer, *s)).min())
+                    .flatten()
+            };
+
+            let assigned_to_project = project_students.get(p);
+            let assigned_to_lecturer = lecturer_students.get(&lecturer.id);
+            let project_full = assigned_to_project.map_or(0, |x| x.len()) >= project.capacity;
+            let lecturer_full = assigned_to_lecturer.map_or(0, |x| x.len()) >= lecturer.capacity;
+
+            let blocks = if !project_full && !lecturer_full {
+                true
+            } else if !project_full {
+                assigned_to_lecturer.map_or(false, |x| x.contains(&s.id))
+                    || worst(assigned_to_lecturer).map_or(false, |w| rank > w)
+            } else {
+                worst(assigned_to_project).map_or(false, |w| rank > w)
+            };
+
+            if blocks {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+// Find a single Proposer or a couple that blocks a matching from Proposers to Responders,
+// if any. Couples block with a pair they prefer when both Responders would take them,
+// where a Responder taking both members of the couple needs room for the two of them
+pub fn couples_blocking_pair(
+    singles: &[ProposerInput],
+    couples: &[CoupleInput],
+    responders: &[ResponderInput],
+    matching: &HashMap<ProposerId, ResponderId>,
+) -> Option<CouplesBlockingPair> {
+    let mut assigned: HashMap<ResponderId, Vec<ProposerId>> = HashMap::new();
+    for (p, r) in matching.iter() {
+        assigned.entry(*r).or_insert_with(Vec::new).push(*p);
+    }
+
+    // Everyone assigned to r, apart from the given Proposers
+    let others = |r: ResponderId, leaving: &[ProposerId]| -> Vec<ProposerId> {
+        assigned
+            .get(&r)
+            .map(|ps| {
+                ps.iter()
+                    .filter(|p| !leaving.contains(p))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_else(Vec::new)
+    };
+
+    for p in singles.iter() {
+        // Only Responders p likes more than its assignment can block
+        let start = match matching.get(&p.id) {
+            Some(r) => match p.preferences.iter().position(|x| x == r) {
+                Some(index) => index + 1,
+                None => 0,
+            },
+            None => 0,
+        };
+
+        for r in p.preferences[start..].iter() {
+            if responders[*r as usize].would_accept(&others(*r, &[p.id]), &[p.id]) {
+                return Some(CouplesBlockingPair::Single {
+                    proposer: p.id,
+                    responder: *r,
+                });
+            }
+        }
+    }
+
+    for c in couples.iter() {
+        let (a, b) = c.members;
+        let current = match (matching.get(&a), matching.get(&b)) {
+            (Some(x), Some(y)) => c.preferences.iter().position(|pair| *pair == (*x, *y)),
+            _ => None,
+        };
+        let start = current.map(|index| index + 1).unwrap_or(0);
+
+        for (x, y) in c.preferences[start..].iter() {
+            let accepted = if x == y {
+                responders[*x as usize].would_accept(&others(*x, &[a, b]), &[a, b])
+            } else {
+                responders[*x as usize].would_accept(&others(*x, &[a, b]), &[a])
+                    && responders[*y as usize].would_accept(&others(*y, &[a, b]), &[b])
+            };
+
+            if accepted {
+                return Some(CouplesBlockingPair::Couple {
+                    members: c.members,
+                    responders: (*x, *y),
+                });
+            }
+        }
+    }
+
+    None
+}
+
+pub fn validate_couples_matching(
+    singles: &[ProposerInput],
+    couples: &[CoupleInput],
+    responders: &[ResponderInput],
+    matching: &HashMap<ProposerId, ResponderId>,
+) -> bool {
+    for (p, r) in matching.iter() {
+        match responders.get(*r as usize) {
+            Some(responder) if responder.accepts(*p) => (),
+            _ => return false,
+        }
+    }
+
+    for p in singles.iter() {
+        if let Some(r) = matching.get(&p.id) {
+            if !p.accepts(*r) {
+                return false;
+            }
+        }
+    }
+
+    for c in couples.iter() {
+        let (a, b) = c.members;
+        match (matching.get(&a), matching.get(&b)) {
+            (Some(x), Some(y)) if c.preferences.contains(&(*x, *y)) => (),
+            (None, None) => (),
+            // Couples are matched together or not at all
+            _ => return false,
+        }
+    }
+
+    for r in responders.iter() {
+        if matching.values().filter(|x| **x == r.id).count() > r.capacity {
+            return false;
+        }
+    }
+
+    couples_blocking_pair(singles, couples, responders, matching).is_none()
+}
+
+// Responders become Proposers and vice versa. Only one-to-one input can be swapped, since
+// Proposers have no capacity or lower quota, so we reject anything else rather than dropping it
+pub fn swap_sides(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+) -> Result<(Vec<ProposerInput>, Vec<ResponderInput>)> {
+    validate_one_to_one_input(proposers, responders)?;
+
+    Ok((
+        responders
+            .iter()
+            .map(|r| ProposerInput::new(r.id, r.preferences.clone()))
+            .collect(),
+        proposers
+            .iter()
+            .map(|p| ResponderInput::new(p.id, p.preferences.clone()))
+            .collect(),
+    ))
+}
+
+pub fn extremal_matchings(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    f: fn(&[ProposerInput], &[ResponderInput], Side) -> Result<HashMap<ProposerId, ResponderId>>,
+) -> Result<ExtremalMatchings> {
+    let proposer_optimal = f(proposers, responders, Side::Proposer)?;
+    let responder_optimal = f(proposers, responders, Side::Responder)?;
+
+    let differing_proposers: BTreeSet<_> = proposers
+        .iter()
+        .map(|p| p.id)
+        .filter(|p| proposer_optimal.get(p) != responder_optimal.get(p))
+        .collect();
+
+    // Everyone matched in one stable matching is matched in all of them, so a Responder's
+    // partner differs exactly when it is matched to one of the differing Proposers
+    let differing_responders = proposer_optimal
+        .iter()
+        .filter(|(p, _)| differing_proposers.contains(p))
+        .map(|(_, r)| *r)
+        .collect();
+
+    Ok(ExtremalMatchings {
+        proposer_optimal,
+        responder_optimal,
+        differing_proposers,
+        differing_responders,
+    })
+}
+
+pub fn break_ties(
+    proposers: &[TiedProposerInput],
+    responders: &[TiedResponderInput],
+    tie_break: &TieBreak,
+) -> Result<(Vec<ProposerInput>, Vec<ResponderInput>)> {
+    let mut rng = match tie_break {
+        TieBreak::Random(seed) => Some(StdRng::seed_from_u64(*seed)),
+        _ => None,
+    };
+
+    // Flatten the indifference classes into a single list ordered by ascending preference
+    let mut flatten = |id: u32, tiers: &[Vec<u32>], master_list: Option<&[u32]>| {
+        let mut preferences = Vec::new();
+
+        for tier in tiers.iter() {
+            let mut tier = tier.clone();
+
+            match tie_break {
+                TieBreak::Deterministic => tier.sort_by(|a, b| b.cmp(a)),
+                TieBreak::Random(_) => tier.shuffle(rng.as_mut().expect("rng known to exist")),
+                TieBreak::MasterList { .. } => {
+                    let master_list = master_list.expect("master list known to exist");
+                    let mut positions = Vec::with_capacity(tier.len());
+                    for x in tier.iter() {
+                        match master_list.iter().position(|m| m == x) {
+                            Some(position) => positions.push((position, *x)),
+                            None => bail!("agent {} is missing from the master list", x),
+                        }
+                    }
+                    positions.sort();
+                    tier = positions.into_iter().map(|(_, x)| x).collect();
+                }
+            }
+
+            for x in tier.into_iter() {
+                if preferences.contains(&x) {
+                    bail!("agent {} received duplicate preferences: {:?}", id, tiers);
+                }
+                preferences.push(x);
+            }
+        }
+
+        Ok(preferences)
+    };
+
+    let (proposer_master, responder_master) = match tie_break {
+        TieBreak::MasterList {
+            proposers,
+            responders,
+        } => (Some(&proposers[..]), Some(&responders[..])),
+        _ => (None, None),
+    };
+
+    let proposers = proposers
+        .iter()
+        .map(|p| {
+            let preferences = flatten(p.id, &p.preferences, responder_master)?;
+            Ok(ProposerInput::new(p.id, preferences))
+        })
+        .collect::<Result<_>>()?;
+    let responders = responders
+        .iter()
+        .map(|r| {
+            let preferences = flatten(r.id, &r.preferences, proposer_master)?;
+            Ok(ResponderInput::new(r.id, preferences))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((proposers, responders))
+}
+
+pub fn weakly_stable_matching(
+    proposers: &[TiedProposerInput],
+    responders: &[TiedResponderInput],
+    tie_break: &TieBreak,
+    side: Side,
+    f: fn(&[ProposerInput], &[ResponderInput], Side) -> Result<HashMap<ProposerId, ResponderId>>,
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    // Any stable matching of the instance with ties broken is weakly stable in
+    // the original instance
+    let (proposers, responders) = break_ties(proposers, responders, tie_break)?;
+    f(&proposers, &responders, side)
+}
+
+pub fn validate_strong_matching(
+    proposers: &[TiedProposerInput],
+    responders: &[TiedResponderInput],
+    matching: &HashMap<u32, u32>,
+) -> bool {
+    is_tied_matching(proposers, responders, matching)
+        && tied_blocking_pair(proposers, responders, matching, Stability::Strong).is_none()
+}
+
+pub fn validate_super_matching(
+    proposers: &[TiedProposerInput],
+    responders: &[TiedResponderInput],
+    matching: &HashMap<u32, u32>,
+) -> bool {
+    is_tied_matching(proposers, responders, matching)
+        && tied_blocking_pair(proposers, responders, matching, Stability::Super).is_none()
+}
+
+fn is_tied_matching(
+    proposers: &[TiedProposerInput],
+    responders: &[TiedResponderInput],
+    matching: &HashMap<u32, u32>,
+) -> bool {
+    let reverse: HashMap<_, _> = HashMap::from_iter(matching.iter().map(|(p, r)| (*r, *p)));
+
+    if reverse.len() != matching.len() {
+        // Some responder was matched with more than one proposer
+        return false;
+    }
+
+    // Only mutually acceptable pairs can be matched
+    matching.iter().all(|(p, r)| {
+        let proposer = proposers.iter().find(|x| x.id == *p);
+        let responder = responders.iter().find(|x| x.id == *r);
+
+        match (proposer, responder) {
+            (Some(proposer), Some(responder)) => {
+                proposer.tier(*r).is_some() && responder.tier(*p).is_some()
+            }
+            _ => false,
+        }
+    })
+}
+
+// Find a pair that blocks the matching under the given notion of stability, if any
+pub fn tied_blocking_pair(
+    proposers: &[TiedProposerInput],
+    responders: &[TiedResponderInput],
+    matching: &HashMap<u32, u32>,
+    stability: Stability,
+) -> Option<(ProposerId, ResponderId)> {
+    let reverse: HashMap<_, _> = HashMap::from_iter(matching.iter().map(|(p, r)| (*r, *p)));
+
+    for p in proposers.iter() {
+        for r in responders.iter() {
+            let proposer_match = matching.get(&p.id()).cloned();
+            let responder_match = reverse.get(&r.id()).cloned();
+
+            if proposer_match == Some(r.id()) {
+                continue;
+            }
+
+            let proposer_prefers_more = p.prefers_more(proposer_match, r.id());
+            let proposer_likes_at_least = p.likes_at_least(proposer_match, r.id());
+            let responder_prefers_more = r.prefers_more(responder_match, p.id());
+            let responder_likes_at_least = r.likes_at_least(responder_match, p.id());
+
+            let blocks = match stability {
+                // Both strictly prefer each other over their assignments
+                Stability::Weak => proposer_prefers_more && responder_prefers_more,
+                // One strictly prefers the other, who is at least indifferent
+                Stability::Strong => {
+                    (proposer_prefers_more && responder_likes_at_least)
+                        || (proposer_likes_at_least && responder_prefers_more)
+                }
+                // Both are at least indifferent
+                Stability::Super => proposer_likes_at_least && responder_likes_at_least,
+            };
+
+            if blocks {
+                return Some((p.id(), r.id()));
+            }
+        }
+    }
+
+    None
+}
+
+pub fn random_input(n: u32, rng: &mut ThreadRng) -> (Vec<ProposerInput>, Vec<ResponderInput>) {
+    random_unbalanced_input(n, n, rng)
+}
+
+pub fn random_unbalanced_input(
+    num_proposers: u32,
+    num_responders: u32,
+    rng: &mut ThreadRng,
+) -> (Vec<ProposerInput>, Vec<ResponderInput>) {
+    let mut proposers = Vec::with_capacity(num_proposers as usize);
+    let mut responders = Vec::with_capacity(num_responders as usize);
+
+    let proposer_ids: Vec<u32> = (0..num_proposers).collect();
+    let responder_ids: Vec<u32> = (0..num_responders).collect();
+
+    for i in 0..num_proposers {
+        let mut proposer_pref = responder_ids.clone();
+        proposer_pref.shuffle(rng);
+        proposers.push(ProposerInput::new(i, proposer_pref));
+    }
+
+    for i in 0..num_responders {
+        let mut responder_pref = proposer_ids.clone();
+        responder_pref.shuffle(rng);
         responders.push(ResponderInput::new(i, responder_pref));
     }
 
     (proposers, responders)
 }
 
+pub fn random_incomplete_input(
+    n: u32,
+    rng: &mut ThreadRng,
+) -> (Vec<ProposerInput>, Vec<ResponderInput>) {
+    let (mut proposers, mut responders) = random_input(n, rng);
+
+    // Every agent only finds a random subset of the other side acceptable
+    for p in proposers.iter_mut() {
+        let len = rng.gen_range(0, n + 1) as usize;
+        p.preferences.truncate(len);
+    }
+
+    for r in responders.iter_mut() {
+        let len = rng.gen_range(0, n + 1) as usize;
+        r.preferences.truncate(len);
+    }
+
+    (proposers, responders)
+}
+
+// Forces at most one and forbids at most two random mutually acceptable pairs. Most
+// forced pairs are in no stable matching, so these are often infeasible
+pub fn random_constraints(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    rng: &mut ThreadRng,
+) -> Constraints {
+    let mut pairs: Vec<(ProposerId, ResponderId)> = proposers
+        .iter()
+        .flat_map(|p| p.preferences.iter().map(move |r| (p.id, *r)))
+        .filter(|(p, r)| responders[*r as usize].accepts(*p))
+        .collect();
+    pairs.shuffle(rng);
+
+    let num_forced = rng.gen_range(0, 2);
+    let num_forbidden = rng.gen_range(0, 3);
+    Constraints::new(
+        pairs.iter().take(num_forced).cloned().collect(),
+        pairs
+            .iter()
+            .skip(num_forced)
+            .take(num_forbidden)
+            .cloned()
+            .collect(),
+    )
+}
+
+pub fn random_many_to_one_input(
+    num_proposers: u32,
+    num_responders: u32,
+    max_capacity: usize,
+    rng: &mut ThreadRng,
+) -> (Vec<ProposerInput>, Vec<ResponderInput>) {
+    let (proposers, responders) = random_unbalanced_input(num_proposers, num_responders, rng);
+
+    let responders = responders
+        .into_iter()
+        .map(|r| {
+            let capacity = rng.gen_range(1, max_capacity + 1);
+            ResponderInput::with_capacity(r.id, r.preferences, capacity)
+        })
+        .collect();
+
+    (proposers, responders)
+}
+
+pub fn random_lower_quota_input(
+    num_proposers: u32,
+    num_responders: u32,
+    max_capacity: usize,
+    rng: &mut ThreadRng,
+) -> (Vec<ProposerInput>, Vec<ResponderInput>) {
+    let (proposers, responders) =
+        random_many_to_one_input(num_proposers, num_responders, max_capacity, rng);
+
+    let responders = responders
+        .into_iter()
+        .map(|r| {
+            let lower_quota = rng.gen_range(0, r.capacity + 1);
+            ResponderInput::with_quotas(r.id, r.preferences, lower_quota, r.capacity)
+        })
+        .collect();
+
+    (proposers, responders)
+}
+
+pub fn random_many_to_many_input(
+    num_proposers: u32,
+    num_responders: u32,
+    max_capacity: usize,
+    rng: &mut ThreadRng,
+) -> (Vec<ManyToManyProposerInput>, Vec<ResponderInput>) {
+    let (proposers, responders) =
+        random_many_to_one_input(num_proposers, num_responders, max_capacity, rng);
+
+    let proposers = proposers
+        .into_iter()
+        .map(|p| {
+            let capacity = rng.gen_range(1, max_capacity + 1);
+            ManyToManyProposerInput::new(p.id, p.preferences, capacity)
+        })
+        .collect();
+
+    (proposers, responders)
+}
+
+pub fn random_couples_input(
+    num_singles: u32,
+    num_couples: u32,
+    num_responders: u32,
+    max_capacity: usize,
+    rng: &mut ThreadRng,
+) -> (Vec<ProposerInput>, Vec<CoupleInput>, Vec<ResponderInput>) {
+    let num_proposers = num_singles + 2 * num_couples;
+    let (proposers, responders) =
+        random_many_to_one_input(num_proposers, num_responders, max_capacity, rng);
+
+    // The first Proposers are single, and the rest are split into couples
+    let singles = proposers.into_iter().take(num_singles as usize).collect();
+
+    let mut pairs: Vec<_> = (0..num_responders)
+        .flat_map(|x| (0..num_responders).map(move |y| (x, y)))
+        .collect();
+    let couples = (0..num_couples)
+        .map(|i| {
+            pairs.shuffle(rng);
+            let len = rng.gen_range(1, pairs.len() + 1);
+            let members = (num_singles + 2 * i, num_singles + 2 * i + 1);
+            CoupleInput::new(members, pairs[..len].to_vec())
+        })
+        .collect();
+
+    (singles, couples, responders)
+}
+
+pub fn random_roommates_input(n: u32, rng: &mut ThreadRng) -> Vec<RoommateInput> {
+    (0..n)
+        .map(|i| {
+            let mut preferences: Vec<u32> = (0..n).filter(|j| *j != i).collect();
+            preferences.shuffle(rng);
+            RoommateInput::new(i, preferences)
+        })
+        .collect()
+}
+
+pub fn random_spa_input(
+    num_students: u32,
+    num_projects: u32,
+    num_lecturers: u32,
+    max_capacity: usize,
+    rng: &mut ThreadRng,
+) -> (Vec<StudentInput>, Vec<ProjectInput>, Vec<LecturerInput>) {
+    let projects: Vec<_> = (0..num_projects)
+        .map(|i| {
+            let lecturer = rng.gen_range(0, num_lecturers);
+            ProjectInput::new(i, lecturer, rng.gen_range(1, max_capacity + 1))
+        })
+        .collect();
+
+    let students: Vec<_> = (0..num_students)
+        .map(|i| {
+            let mut preferences: Vec<_> = (0..num_projects).collect();
+            preferences.shuffle(rng);
+            let len = rng.gen_range(0, num_projects + 1);
+            StudentInput::new(i, preferences[..len as usize].to_vec())
+        })
+        .collect();
+
+    let lecturers = (0..num_lecturers)
+        .map(|i| {
+            let offered: Vec<_> = projects.iter().filter(|p| p.lecturer == i).collect();
+
+            // Lecturers rank exactly the Students that find one of their Projects acceptable
+            let mut preferences: Vec<_> = students
+                .iter()
+                .filter(|s| offered.iter().any(|p| s.preferences.contains(&p.id)))
+                .map(|s| s.id)
+                .collect();
+            preferences.shuffle(rng);
+
+            let largest = offered.iter().map(|p| p.capacity).max().unwrap_or(1);
+            let total = offered
+                .iter()
+                .map(|p| p.capacity)
+                .sum::<usize>()
+                .max(largest);
+            LecturerInput::new(i, preferences, rng.gen_range(largest, total + 1))
+        })
+        .collect();
+
+    (students, projects, lecturers)
+}
+
+pub fn random_tied_input(
+    n: u32,
+    rng: &mut ThreadRng,
+) -> (Vec<TiedProposerInput>, Vec<TiedResponderInput>) {
+    let (proposers, responders) = random_input(n, rng);
+
+    // Split every shuffled list into indifference classes of random sizes
+    let mut into_tiers = |preferences: Vec<u32>| {
+        let mut tiers: Vec<Vec<u32>> = Vec::new();
+        for x in preferences.into_iter() {
+            match tiers.last_mut() {
+                Some(tier) if rng.gen_bool(0.5) => tier.push(x),
+                _ => tiers.push(vec![x]),
+            }
+        }
+        tiers
+    };
+
+    let proposers = proposers
+        .into_iter()
+        .map(|p| TiedProposerInput::new(p.id, into_tiers(p.preferences)))
+        .collect();
+    let responders = responders
+        .into_iter()
+        .map(|r| TiedResponderInput::new(r.id, into_tiers(r.preferences)))
+        .collect();
+
+    (proposers, responders)
+}
+
 pub fn basic_test(
-    f: fn(&[ProposerInput], &[ResponderInput]) -> Result<HashMap<ProposerId, ResponderId>>,
+    f: fn(&[ProposerInput], &[ResponderInput], Side) -> Result<HashMap<ProposerId, ResponderId>>,
 ) {
     let mut rng = rand::thread_rng();
     for n in 1..100 {
         let (proposers, responders) = random_input(n, &mut rng);
 
-        let matching = f(&proposers, &responders).unwrap();
+        for side in [Side::Proposer, Side::Responder].iter() {
+            let matching = f(&proposers, &responders, *side).unwrap();
+
+            assert_eq!(validate_matching(&proposers, &responders, &matching), true);
+        }
+    }
+}
+
+pub fn extremal_test(
+    f: fn(&[ProposerInput], &[ResponderInput], Side) -> Result<HashMap<ProposerId, ResponderId>>,
+) {
+    let mut rng = rand::thread_rng();
+    for n in 1..100 {
+        let (proposers, responders) = random_input(n, &mut rng);
+
+        let result = extremal_matchings(&proposers, &responders, f).unwrap();
+
+        assert_eq!(
+            validate_matching(&proposers, &responders, &result.proposer_optimal),
+            true
+        );
+        assert_eq!(
+            validate_matching(&proposers, &responders, &result.responder_optimal),
+            true
+        );
+
+        // Proposers never do worse in the proposer-optimal matching, and Responders never
+        // do worse in the responder-optimal matching
+        for p in proposers.iter() {
+            let optimal = result.proposer_optimal[&p.id];
+            let pessimal = result.responder_optimal[&p.id];
+            assert_eq!(p.prefers_more(optimal, pessimal), false);
+            assert_eq!(
+                result.differing_proposers.contains(&p.id),
+                optimal != pessimal
+            );
+        }
+
+        let proposer_optimal: HashMap<_, _> = result
+            .proposer_optimal
+            .iter()
+            .map(|(p, r)| (*r, *p))
+            .collect();
+        let responder_optimal: HashMap<_, _> = result
+            .responder_optimal
+            .iter()
+            .map(|(p, r)| (*r, *p))
+            .collect();
+        for r in responders.iter() {
+            let optimal = responder_optimal[&r.id];
+            let pessimal = proposer_optimal[&r.id];
+            assert_eq!(r.prefers_more(optimal, pessimal), false);
+            assert_eq!(
+                result.differing_responders.contains(&r.id),
+                optimal != pessimal
+            );
+        }
+    }
+}
+
+pub fn incomplete_test(f: fn(&[ProposerInput], &[ResponderInput], Side) -> Result<MatchingResult>) {
+    let mut rng = rand::thread_rng();
+    for n in 1..100 {
+        let (proposers, responders) = random_incomplete_input(n, &mut rng);
+
+        for side in [Side::Proposer, Side::Responder].iter() {
+            let result = f(&proposers, &responders, *side).unwrap();
+
+            assert_eq!(
+                validate_matching(&proposers, &responders, &result.matching),
+                true
+            );
+            assert_eq!(
+                result.matching.len() + result.unmatched_proposers.len(),
+                proposers.len()
+            );
+            assert_eq!(
+                result.matching.len() + result.unmatched_responders.len(),
+                responders.len()
+            );
+        }
+    }
+}
+
+pub fn unbalanced_test(f: fn(&[ProposerInput], &[ResponderInput], Side) -> Result<MatchingResult>) {
+    let mut rng = rand::thread_rng();
+    for num_proposers in 1..30 {
+        for num_responders in 1..30 {
+            let (proposers, responders) =
+                random_unbalanced_input(num_proposers, num_responders, &mut rng);
+
+            for side in [Side::Proposer, Side::Responder].iter() {
+                let result = f(&proposers, &responders, *side).unwrap();
+
+                assert_eq!(
+                    validate_matching(&proposers, &responders, &result.matching),
+                    true
+                );
+
+                // With complete lists everyone on the smaller side gets matched
+                let matched = std::cmp::min(num_proposers, num_responders) as usize;
+                assert_eq!(result.matching.len(), matched);
+                assert_eq!(result.unmatched_proposers.len(), proposers.len() - matched);
+                assert_eq!(
+                    result.unmatched_responders.len(),
+                    responders.len() - matched
+                );
+            }
+        }
+    }
+}
+
+pub fn invalid_input_test<T>(f: fn(&[ProposerInput], &[ResponderInput], Side) -> Result<T>) {
+    let valid = || {
+        (
+            vec![
+                ProposerInput::new(0, vec![0, 1]),
+                ProposerInput::new(1, vec![1, 0]),
+            ],
+            vec![
+                ResponderInput::new(0, vec![1, 0]),
+                ResponderInput::new(1, vec![0, 1]),
+            ],
+        )
+    };
+
+    for side in [Side::Proposer, Side::Responder].iter() {
+        let (proposers, responders) = valid();
+        assert_eq!(f(&proposers, &responders, *side).is_ok(), true);
+
+        // Ids and preferences have to be in range
+        let (mut proposers, responders) = valid();
+        proposers[1].id = 2;
+        assert_eq!(f(&proposers, &responders, *side).is_err(), true);
+
+        // Ids have to match their position, since agents are looked up by id
+        let (proposers, mut responders) = valid();
+        responders.swap(0, 1);
+        assert_eq!(f(&proposers, &responders, *side).is_err(), true);
 
-        assert_eq!(validate_matching(&proposers, &responders, &matching), true);
+        let (proposers, mut responders) = valid();
+        responders[0].preferences.push(2);
+        assert_eq!(f(&proposers, &responders, *side).is_err(), true);
+
+        // Nobody can be listed twice
+        let (mut proposers, responders) = valid();
+        proposers[0].preferences = vec![0, 1, 0];
+        assert_eq!(f(&proposers, &responders, *side).is_err(), true);
+
+        let (proposers, mut responders) = valid();
+        responders[1].preferences = vec![1, 1];
+        assert_eq!(f(&proposers, &responders, *side).is_err(), true);
+
+        // One-to-one engines have no room for capacities or lower quotas
+        let (proposers, mut responders) = valid();
+        responders[0] = ResponderInput::with_capacity(0, vec![1, 0], 2);
+        assert_eq!(f(&proposers, &responders, *side).is_err(), true);
+
+        let (proposers, mut responders) = valid();
+        responders[0] = ResponderInput::with_quotas(0, vec![1, 0], 1, 1);
+        assert_eq!(f(&proposers, &responders, *side).is_err(), true);
+    }
+}
+
+pub fn constraints_test(
+    f: fn(
+        &[ProposerInput],
+        &[ResponderInput],
+        Side,
+        &Constraints,
+    ) -> Result<HashMap<ProposerId, ResponderId>>,
+) {
+    // Proposers 0 and 1 have two stable matchings with Responders 0 and 1, and Proposer 2
+    // and Responder 2 only accept each other
+    let proposers = vec![
+        ProposerInput::new(0, vec![1, 0]),
+        ProposerInput::new(1, vec![0, 1]),
+        ProposerInput::new(2, vec![2]),
+    ];
+    let responders = vec![
+        ResponderInput::new(0, vec![0, 1]),
+        ResponderInput::new(1, vec![1, 0]),
+        ResponderInput::new(2, vec![2]),
+    ];
+    let responder_optimal: HashMap<_, _> = vec![(0, 1), (1, 0), (2, 2)].into_iter().collect();
+
+    // The matching, or the constraint to blame, when Proposers and when Responders propose
+    let cases = vec![
+        (
+            Constraints::new(vec![(0, 1)], vec![]),
+            Ok(responder_optimal.clone()),
+            Ok(responder_optimal.clone()),
+        ),
+        (
+            Constraints::new(vec![], vec![(0, 0)]),
+            Ok(responder_optimal.clone()),
+            Ok(responder_optimal.clone()),
+        ),
+        // 2 and 2 block every matching that keeps them apart
+        (
+            Constraints::new(vec![], vec![(2, 2)]),
+            Err(Infeasible::Forbidden(2, 2)),
+            Err(Infeasible::Forbidden(2, 2)),
+        ),
+        // Matching 0 with 1 leaves 1 and 0 to block, and which constraint gets the blame
+        // depends on who proposes
+        (
+            Constraints::new(vec![(0, 1)], vec![(1, 0)]),
+            Err(Infeasible::Forbidden(1, 0)),
+            Err(Infeasible::Forced(0, 1)),
+        ),
+        // Responder 0 can only be matched with one of the Proposers forced onto it
+        (
+            Constraints::new(vec![(0, 0), (1, 0)], vec![]),
+            Err(Infeasible::Forced(0, 0)),
+            Err(Infeasible::Forced(1, 0)),
+        ),
+    ];
+
+    for (constraints, proposer_side, responder_side) in cases.iter() {
+        for (side, expected) in [
+            (Side::Proposer, proposer_side),
+            (Side::Responder, responder_side),
+        ]
+        .iter()
+        {
+            let result = f(&proposers, &responders, *side, constraints).map_err(|e| {
+                *e.downcast_ref::<Infeasible>()
+                    .expect("error known to be infeasible")
+            });
+            assert_eq!(&result, *expected);
+        }
+    }
+}
+
+pub fn ties_test(
+    f: fn(&[ProposerInput], &[ResponderInput], Side) -> Result<HashMap<ProposerId, ResponderId>>,
+) {
+    // Only pairs that strictly prefer each other block a matching with ties. Proposer 0
+    // and Responder 0 like everyone equally
+    let proposers = vec![
+        TiedProposerInput::new(0, vec![vec![0, 1]]),
+        TiedProposerInput::new(1, vec![vec![0], vec![1]]),
+    ];
+    let responders = vec![
+        TiedResponderInput::new(0, vec![vec![0, 1]]),
+        TiedResponderInput::new(1, vec![vec![1], vec![0]]),
+    ];
+    let matching: HashMap<_, _> = vec![(0, 0), (1, 1)].into_iter().collect();
+    assert_eq!(validate_matching(&proposers, &responders, &matching), true);
+    let matching: HashMap<_, _> = vec![(1, 0)].into_iter().collect();
+    assert_eq!(validate_matching(&proposers, &responders, &matching), false);
+
+    let mut rng = rand::thread_rng();
+    for n in 1..50 {
+        let (proposers, responders) = random_tied_input(n, &mut rng);
+
+        let mut proposer_master: Vec<u32> = (0..n).collect();
+        proposer_master.shuffle(&mut rng);
+        let mut responder_master: Vec<u32> = (0..n).collect();
+        responder_master.shuffle(&mut rng);
+
+        let tie_breaks = vec![
+            TieBreak::Deterministic,
+            TieBreak::Random(rng.gen()),
+            TieBreak::MasterList {
+                proposers: proposer_master,
+                responders: responder_master,
+            },
+        ];
+
+        for tie_break in tie_breaks.iter() {
+            for side in [Side::Proposer, Side::Responder].iter() {
+                let matching =
+                    weakly_stable_matching(&proposers, &responders, tie_break, *side, f).unwrap();
+
+                assert_eq!(validate_matching(&proposers, &responders, &matching), true);
+            }
+        }
     }
 }
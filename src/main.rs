@@ -1,15 +1,25 @@
-use std::collections::HashMap;
-
+mod couples;
 mod differential;
+mod flow;
+mod hospitals_residents;
 mod input;
+mod many_to_many;
+mod median;
+mod optimal;
+mod popular;
+mod roommates;
+mod rotations;
+mod spa;
 mod stable_marriage;
+mod ties;
+mod ttc;
 mod v0;
 mod v1;
 mod v2;
 mod v3;
 mod v4;
 
-use input::{ProposerInput, ResponderInput};
+use input::{ProposerInput, ResponderInput, Side};
 use stable_marriage::{Suited, Suitor};
 
 fn main() {
@@ -52,7 +62,7 @@ fn main() {
         Suited::new(5, vec![0, 1, 2, 3, 4, 5]),
     ];
 
-    differential::generate_match(suitors, suiteds);
+    differential::generate_match(suitors, suiteds, Side::Proposer);
 
     let proposers = vec![
         ProposerInput::new(0, vec![3, 5, 4, 2, 1, 0]),
@@ -72,28 +82,18 @@ fn main() {
         ResponderInput::new(5, vec![0, 1, 2, 3, 4, 5]),
     ];
 
-    let matching: HashMap<u32, u32> = v0::stable_matching(&proposers, &responders).unwrap();
-    let valid: bool = input::validate_matching(&proposers, &responders, &matching);
+    let result = input::extremal_matchings(&proposers, &responders, v0::stable_matching).unwrap();
+
+    for matching in [&result.proposer_optimal, &result.responder_optimal].iter() {
+        let valid: bool = input::validate_matching(&proposers, &responders, matching);
+        println!("Matching: {:?} valid: {}", matching, valid);
+    }
 
-    println!("Matching: {:?} valid: {}", matching, valid);
+    println!(
+        "Proposers with different partners: {:?} Responders with different partners: {:?}",
+        result.differing_proposers, result.differing_responders
+    );
 
-    let proposers: Vec<ProposerInput> = proposers
-        .iter()
-        .map(|p| {
-            let mut pref = p.preferences.clone();
-            pref.reverse();
-            ProposerInput::new(p.id, pref)
-        })
-        .collect();
-    let responders: Vec<ResponderInput> = responders
-        .iter()
-        .map(|r| {
-            let mut pref = r.preferences.clone();
-            pref.reverse();
-            ResponderInput::new(r.id, pref)
-        })
-        .collect();
-    let matching: HashMap<u32, u32> = v0::stable_matching(&proposers, &responders).unwrap();
-    let valid: bool = input::validate_matching(&proposers, &responders, &matching);
-    println!("Matching: {:?} valid: {}", matching, valid);
+    let poset = rotations::RotationPoset::new(&proposers, &responders).unwrap();
+    println!("{}", poset.to_dot());
 }
@@ -0,0 +1,171 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::Result;
+
+use crate::hospitals_residents::{deferred_acceptance, Proposer, Responder};
+use crate::input::{
+    validate_input, ManyToManyProposerInput, ProposerId, ResponderId, ResponderInput,
+};
+
+// Deferred acceptance where both sides can take several partners. Every Proposer keeps
+// as many proposals out as it has capacity for, and Responders hold on to the best ones
+// up to their capacity. With responsive preferences the result is pairwise stable, and
+// the best pairwise stable matching for the Proposers
+pub fn stable_matching(
+    proposers_input: &[ManyToManyProposerInput],
+    responders_input: &[ResponderInput],
+) -> Result<HashMap<ProposerId, BTreeSet<ResponderId>>> {
+    validate_input(proposers_input, responders_input)?;
+
+    let num_proposers = proposers_input.len();
+    let num_responders = responders_input.len();
+    let proposers: Vec<_> = proposers_input
+        .iter()
+        .map(|p| {
+            Proposer::new(
+                p.id,
+                &p.preferences,
+                p.capacity,
+                num_proposers,
+                num_responders,
+            )
+        })
+        .collect::<Result<_>>()?;
+    let responders: Vec<_> = responders_input
+        .iter()
+        .map(|r| {
+            Responder::new(
+                r.id,
+                &r.preferences,
+                r.capacity,
+                num_responders,
+                num_proposers,
+            )
+        })
+        .collect::<Result<_>>()?;
+
+    // Return a mapping from ProposerId : set(ResponderId)
+    let mut assignment: HashMap<ProposerId, BTreeSet<ResponderId>> = HashMap::new();
+    for (r, accepted) in deferred_acceptance(proposers, responders)? {
+        for p in accepted.into_iter() {
+            assignment.entry(p).or_insert_with(BTreeSet::new).insert(r);
+        }
+    }
+
+    Ok(assignment)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{ManyToManyProposerInput, Side};
+
+    #[test]
+    fn basic_many_to_many_test() {
+        let mut rng = rand::thread_rng();
+        for num_proposers in 1..30 {
+            for num_responders in 1..10 {
+                let (proposers, responders) = crate::input::random_many_to_many_input(
+                    num_proposers,
+                    num_responders,
+                    4,
+                    &mut rng,
+                );
+
+                let assignment = super::stable_matching(&proposers, &responders).unwrap();
+
+                assert_eq!(
+                    crate::input::validate_many_to_many_matching(
+                        &proposers,
+                        &responders,
+                        &assignment
+                    ),
+                    true
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_many_to_many_test() {
+        use crate::input::{ManyToManyProposerInput, ResponderInput};
+
+        let valid = || {
+            (
+                vec![
+                    ManyToManyProposerInput::new(0, vec![0, 1], 2),
+                    ManyToManyProposerInput::new(1, vec![1, 0], 2),
+                ],
+                vec![
+                    ResponderInput::with_capacity(0, vec![1, 0], 2),
+                    ResponderInput::with_capacity(1, vec![0, 1], 1),
+                ],
+            )
+        };
+
+        let (proposers, responders) = valid();
+        assert_eq!(
+            super::stable_matching(&proposers, &responders).is_ok(),
+            true
+        );
+
+        // Ids have to be in range and match their position
+        let (mut proposers, responders) = valid();
+        proposers[1].id = 2;
+        assert_eq!(
+            super::stable_matching(&proposers, &responders).is_err(),
+            true
+        );
+
+        let (proposers, mut responders) = valid();
+        responders.swap(0, 1);
+        assert_eq!(
+            super::stable_matching(&proposers, &responders).is_err(),
+            true
+        );
+
+        // Preferences have to be in range, and list nobody twice. A Proposer listing the
+        // same Responder twice would hold one of its slots forever
+        let (mut proposers, responders) = valid();
+        proposers[0].preferences.push(2);
+        assert_eq!(
+            super::stable_matching(&proposers, &responders).is_err(),
+            true
+        );
+
+        let (mut proposers, responders) = valid();
+        proposers[0].preferences = vec![0, 1, 0];
+        assert_eq!(
+            super::stable_matching(&proposers, &responders).is_err(),
+            true
+        );
+
+        let (proposers, mut responders) = valid();
+        responders[1].preferences = vec![1, 1];
+        assert_eq!(
+            super::stable_matching(&proposers, &responders).is_err(),
+            true
+        );
+    }
+
+    #[test]
+    fn one_to_one_many_to_many_test() {
+        let mut rng = rand::thread_rng();
+        for n in 1..30 {
+            let (proposers, responders) = crate::input::random_incomplete_input(n, &mut rng);
+
+            // With every capacity at one this is the stable marriage problem
+            let many_to_many: Vec<_> = proposers
+                .iter()
+                .map(|p| ManyToManyProposerInput::new(p.id, p.preferences.clone(), 1))
+                .collect();
+            let assignment = super::stable_matching(&many_to_many, &responders).unwrap();
+            let expected =
+                crate::v0::stable_matching(&proposers, &responders, Side::Proposer).unwrap();
+
+            assert_eq!(assignment.len(), expected.len());
+            for (p, r) in expected.iter() {
+                assert_eq!(assignment[p].iter().collect::<Vec<_>>(), vec![r]);
+            }
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+use crate::input::{ProposerId, ProposerInput, ResponderId, ResponderInput};
+use crate::rotations::all_stable_matchings;
+
+#[derive(Debug)]
+pub struct MedianMatching {
+    pub matching: HashMap<ProposerId, ResponderId>,
+    // Number of stable matchings the partners were taken from
+    pub count: usize,
+    // Every partner each agent has across all stable matchings, along with how many of
+    // them they show up in. Partners are ordered from the proposer-optimal end to the
+    // responder-optimal end, so Proposers go from their best partner to their worst,
+    // and Responders from their worst to their best
+    pub proposer_partners: HashMap<ProposerId, Vec<(ResponderId, usize)>>,
+    pub responder_partners: HashMap<ResponderId, Vec<(ProposerId, usize)>>,
+}
+
+// Collapse a sorted list of partners into (partner, count) pairs
+fn distribution(partners: &[u32]) -> Vec<(u32, usize)> {
+    let mut distribution: Vec<(u32, usize)> = Vec::new();
+
+    for partner in partners.iter() {
+        match distribution.last_mut() {
+            Some((last, count)) if last == partner => *count += 1,
+            _ => distribution.push((*partner, 1)),
+        }
+    }
+
+    distribution
+}
+
+// Lists all k stable matchings, and gives every Proposer the i-th best of the k partners
+// they have across them, counting duplicates. This is always a stable matching, where
+// i = 1 is the proposer-optimal matching and i = k the responder-optimal one. Every
+// Responder ends up with the i-th worst of their partners. There can be exponentially
+// many stable matchings, so we fail once there are more than limit of them
+pub fn generalized_median_stable_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    i: usize,
+    limit: usize,
+) -> Result<MedianMatching> {
+    let matchings = enumerate(proposers, responders, limit)?;
+    median(proposers, responders, &matchings, i)
+}
+
+pub fn median_stable_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    limit: usize,
+) -> Result<MedianMatching> {
+    let matchings = enumerate(proposers, responders, limit)?;
+    median(proposers, responders, &matchings, (matchings.len() + 1) / 2)
+}
+
+fn enumerate(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    limit: usize,
+) -> Result<Vec<HashMap<ProposerId, ResponderId>>> {
+    let matchings: Vec<_> = all_stable_matchings(proposers, responders)?
+        .take(limit.saturating_add(1))
+        .collect();
+
+    if matchings.len() > limit {
+        bail!(
+            "received an instance with more than {} stable matchings",
+            limit
+        );
+    }
+
+    Ok(matchings)
+}
+
+fn median(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    matchings: &[HashMap<ProposerId, ResponderId>],
+    i: usize,
+) -> Result<MedianMatching> {
+    let count = matchings.len();
+
+    if i == 0 || i > count {
+        bail!(
+            "received invalid median: {} for an instance with {} stable matchings",
+            i,
+            count
+        );
+    }
+
+    // Position of every agent in each preference list, where higher is better
+    let position = |preferences: &[u32], other: u32| {
+        preferences
+            .iter()
+            .position(|x| *x == other)
+            .expect("partner known to be acceptable")
+    };
+
+    let mut matching = HashMap::new();
+    let mut proposer_partners = HashMap::new();
+    let mut responder_partners = HashMap::new();
+
+    // Everyone matched in one stable matching is matched in all of them
+    for p in matchings[0].keys() {
+        let preferences = &proposers[*p as usize].preferences;
+        let mut partners: Vec<_> = matchings.iter().map(|m| m[p]).collect();
+        partners.sort_by_key(|r| std::cmp::Reverse(position(preferences, *r)));
+
+        matching.insert(*p, partners[i - 1]);
+        proposer_partners.insert(*p, distribution(&partners));
+    }
+
+    let inverted: Vec<HashMap<ResponderId, ProposerId>> = matchings
+        .iter()
+        .map(|m| m.iter().map(|(p, r)| (*r, *p)).collect())
+        .collect();
+
+    for r in inverted[0].keys() {
+        let preferences = &responders[*r as usize].preferences;
+        let mut partners: Vec<_> = inverted.iter().map(|m| m[r]).collect();
+        partners.sort_by_key(|p| position(preferences, *p));
+
+        responder_partners.insert(*r, distribution(&partners));
+    }
+
+    Ok(MedianMatching {
+        matching,
+        count,
+        proposer_partners,
+        responder_partners,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{validate_matching, Side};
+
+    const LIMIT: usize = 100_000;
+
+    #[test]
+    fn median_test() {
+        let mut rng = rand::thread_rng();
+        for n in 1..30 {
+            let (proposers, responders) = crate::input::random_input(n, &mut rng);
+
+            let median = super::median_stable_matching(&proposers, &responders, LIMIT).unwrap();
+            let count = median.count;
+
+            for i in 1..=count {
+                let result =
+                    super::generalized_median_stable_matching(&proposers, &responders, i, LIMIT)
+                        .unwrap();
+                assert_eq!(
+                    validate_matching(&proposers, &responders, &result.matching),
+                    true
+                );
+
+                // Responders get the i-th worst of their partners
+                for (r, partners) in result.responder_partners.iter() {
+                    let mut expanded = Vec::new();
+                    for (p, times) in partners.iter() {
+                        expanded.extend(std::iter::repeat(*p).take(*times));
+                    }
+                    assert_eq!(expanded.len(), count);
+                    assert_eq!(result.matching[&expanded[i - 1]], *r);
+                }
+
+                if i == 1 {
+                    let optimal =
+                        crate::v0::stable_matching(&proposers, &responders, Side::Proposer);
+                    assert_eq!(result.matching, optimal.unwrap());
+                }
+                if i == count {
+                    let pessimal =
+                        crate::v0::stable_matching(&proposers, &responders, Side::Responder);
+                    assert_eq!(result.matching, pessimal.unwrap());
+                }
+            }
+
+            assert_eq!(
+                super::generalized_median_stable_matching(&proposers, &responders, 0, LIMIT)
+                    .is_err(),
+                true
+            );
+            assert_eq!(
+                super::generalized_median_stable_matching(
+                    &proposers,
+                    &responders,
+                    count + 1,
+                    LIMIT
+                )
+                .is_err(),
+                true
+            );
+
+            // Instances with more stable matchings than the limit are rejected
+            assert_eq!(
+                super::median_stable_matching(&proposers, &responders, count).is_ok(),
+                true
+            );
+            assert_eq!(
+                super::median_stable_matching(&proposers, &responders, count - 1).is_err(),
+                true
+            );
+        }
+    }
+}
@@ -0,0 +1,701 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{bail, Result};
+
+use crate::flow::Lexicographic;
+use crate::input::{ProposerId, ProposerInput, ResponderId, ResponderInput};
+use crate::rotations::RotationPoset;
+
+// Where other shows up in a list ordered by ascending preference, where the most
+// preferred entry has rank 1
+fn rank(preferences: &[u32], other: u32) -> usize {
+    let index = preferences
+        .iter()
+        .position(|x| *x == other)
+        .expect("partner known to be acceptable");
+    preferences.len() - index
+}
+
+// Sum of the ranks both agents give each other
+pub fn rank_sum(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    matching: &HashMap<ProposerId, ResponderId>,
+) -> usize {
+    matching
+        .iter()
+        .map(|(p, r)| {
+            rank(&proposers[*p as usize].preferences, *r)
+                + rank(&responders[*r as usize].preferences, *p)
+        })
+        .sum()
+}
+
+// Worst rank any matched agent gives their partner
+pub fn regret(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    matching: &HashMap<ProposerId, ResponderId>,
+) -> usize {
+    matching
+        .iter()
+        .map(|(p, r)| {
+            std::cmp::max(
+                rank(&proposers[*p as usize].preferences, *r),
+                rank(&responders[*r as usize].preferences, *p),
+            )
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+#[derive(Debug)]
+pub struct MinimumRegret {
+    pub matching: HashMap<ProposerId, ResponderId>,
+    pub regret: usize,
+}
+
+// How many agents on each side got their first choice, second choice, and so on
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RankProfile {
+    pub proposers: Vec<usize>,
+    pub responders: Vec<usize>,
+}
+
+impl RankProfile {
+    // Both sides put together
+    pub fn total(&self) -> Vec<usize> {
+        let len = std::cmp::max(self.proposers.len(), self.responders.len());
+        (0..len)
+            .map(|k| {
+                self.proposers.get(k).cloned().unwrap_or(0)
+                    + self.responders.get(k).cloned().unwrap_or(0)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct RankedMatching {
+    pub matching: HashMap<ProposerId, ResponderId>,
+    pub profile: RankProfile,
+}
+
+pub fn rank_profile(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    matching: &HashMap<ProposerId, ResponderId>,
+) -> RankProfile {
+    let max_rank = std::cmp::max(
+        proposers
+            .iter()
+            .map(|p| p.preferences.len())
+            .max()
+            .unwrap_or(0),
+        responders
+            .iter()
+            .map(|r| r.preferences.len())
+            .max()
+            .unwrap_or(0),
+    );
+    let mut profile = RankProfile {
+        proposers: vec![0; max_rank],
+        responders: vec![0; max_rank],
+    };
+
+    for (p, r) in matching.iter() {
+        profile.proposers[rank(&proposers[*p as usize].preferences, *r) - 1] += 1;
+        profile.responders[rank(&responders[*r as usize].preferences, *p) - 1] += 1;
+    }
+
+    profile
+}
+
+#[derive(Debug)]
+pub struct BalancedMatching {
+    pub matching: HashMap<ProposerId, ResponderId>,
+    // Sum of the ranks every Proposer gives their partner, and vice versa
+    pub proposer_cost: usize,
+    pub responder_cost: usize,
+    // Difference between the two
+    pub imbalance: usize,
+}
+
+impl BalancedMatching {
+    fn new(
+        proposers: &[ProposerInput],
+        responders: &[ResponderInput],
+        matching: HashMap<ProposerId, ResponderId>,
+    ) -> Self {
+        let profile = rank_profile(proposers, responders, &matching);
+        let cost = |counts: &[usize]| -> usize {
+            counts
+                .iter()
+                .enumerate()
+                .map(|(k, count)| (k + 1) * count)
+                .sum()
+        };
+        let proposer_cost = cost(&profile.proposers);
+        let responder_cost = cost(&profile.responders);
+
+        BalancedMatching {
+            matching,
+            proposer_cost,
+            responder_cost,
+            imbalance: (proposer_cost as i64 - responder_cost as i64).abs() as usize,
+        }
+    }
+}
+
+// Stable matching minimising the total cost of every matched (Proposer, Responder) pair
+pub fn minimum_cost_stable_matching<F>(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    cost: F,
+) -> Result<HashMap<ProposerId, ResponderId>>
+where
+    F: Fn(ProposerId, ResponderId) -> i64,
+{
+    let poset = RotationPoset::new(proposers, responders)?;
+
+    // Eliminating a rotation moves every p_i from r_i to r_{i+1}, and leaves every
+    // other pair alone
+    let weights: Vec<i64> = poset
+        .rotations
+        .iter()
+        .map(|rotation| {
+            let pairs = &rotation.pairs;
+            pairs
+                .iter()
+                .enumerate()
+                .map(|(i, (p, r))| cost(*p, pairs[(i + 1) % pairs.len()].1) - cost(*p, *r))
+                .sum()
+        })
+        .collect();
+
+    let closed = poset.minimum_weight_closed_set(&weights);
+    Ok(poset.matching(&closed))
+}
+
+pub fn egalitarian_stable_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    minimum_cost_stable_matching(proposers, responders, |p, r| {
+        (rank(&proposers[p as usize].preferences, r) + rank(&responders[r as usize].preferences, p))
+            as i64
+    })
+}
+
+pub fn minimum_regret_stable_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+) -> Result<MinimumRegret> {
+    let poset = RotationPoset::new(proposers, responders)?;
+    let predecessors = poset.predecessors();
+
+    // Every partner each agent goes through as rotations get eliminated, along with the
+    // rotation that matched them or None for the proposer-optimal matching. Proposers
+    // only get worse partners and Responders only get better ones
+    let mut proposer_partners: HashMap<ProposerId, Vec<(Option<usize>, ResponderId)>> = poset
+        .proposer_optimal
+        .iter()
+        .map(|(p, r)| (*p, vec![(None, *r)]))
+        .collect();
+    let mut responder_partners: HashMap<ResponderId, Vec<(Option<usize>, ProposerId)>> = poset
+        .proposer_optimal
+        .iter()
+        .map(|(p, r)| (*r, vec![(None, *p)]))
+        .collect();
+
+    for (index, rotation) in poset.rotations.iter().enumerate() {
+        let pairs = &rotation.pairs;
+        for (i, (p, _)) in pairs.iter().enumerate() {
+            let next = pairs[(i + 1) % pairs.len()].1;
+            proposer_partners
+                .get_mut(p)
+                .expect("proposer known to be matched")
+                .push((Some(index), next));
+            responder_partners
+                .get_mut(&next)
+                .expect("responder known to be matched")
+                .push((Some(index), *p));
+        }
+    }
+
+    let max_rank = std::cmp::max(
+        proposers
+            .iter()
+            .map(|p| p.preferences.len())
+            .max()
+            .unwrap_or(0),
+        responders
+            .iter()
+            .map(|r| r.preferences.len())
+            .max()
+            .unwrap_or(0),
+    );
+
+    // Find the smallest regret k some stable matching can get. Every Responder needs the
+    // rotation that first gets them a partner ranked k or better, and no Proposer can
+    // have the rotation that first gets them a partner ranked worse than k
+    for k in 0..=max_rank {
+        let mut required = BTreeSet::new();
+        let mut forbidden = BTreeSet::new();
+        let mut feasible = true;
+
+        for (r, partners) in responder_partners.iter() {
+            let preferences = &responders[*r as usize].preferences;
+            match partners.iter().find(|(_, p)| rank(preferences, *p) <= k) {
+                Some((Some(rotation), _)) => {
+                    required.insert(*rotation);
+                }
+                Some((None, _)) => (),
+                None => feasible = false,
+            }
+        }
+
+        for (p, partners) in proposer_partners.iter() {
+            let preferences = &proposers[*p as usize].preferences;
+            match partners.iter().find(|(_, r)| rank(preferences, *r) > k) {
+                Some((Some(rotation), _)) => {
+                    forbidden.insert(*rotation);
+                }
+                Some((None, _)) => feasible = false,
+                None => (),
+            }
+        }
+
+        if !feasible {
+            continue;
+        }
+
+        // The smallest closed set with every required rotation. It's closed, so if it
+        // has none of the forbidden rotations it also has none of their successors
+        let mut closed = BTreeSet::new();
+        let mut stack: Vec<_> = required.into_iter().collect();
+        while let Some(rotation) = stack.pop() {
+            if closed.insert(rotation) {
+                stack.extend(predecessors[rotation].iter().cloned());
+            }
+        }
+
+        if closed.is_disjoint(&forbidden) {
+            let matching = poset.matching(&closed);
+            return Ok(MinimumRegret {
+                regret: regret(proposers, responders, &matching),
+                matching,
+            });
+        }
+    }
+
+    // Every stable matching has regret at most the longest preference list, so we only
+    // get here if the rotation poset doesn't describe the instance
+    bail!("found no stable matching with regret at most the longest preference list")
+}
+
+// How eliminating each rotation changes the number of agents with each rank, where
+// index k counts the agents whose partner has rank k + 1
+fn profile_changes(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    poset: &RotationPoset,
+) -> Vec<Vec<i64>> {
+    let max_rank = std::cmp::max(
+        proposers
+            .iter()
+            .map(|p| p.preferences.len())
+            .max()
+            .unwrap_or(0),
+        responders
+            .iter()
+            .map(|r| r.preferences.len())
+            .max()
+            .unwrap_or(0),
+    );
+
+    poset
+        .rotations
+        .iter()
+        .map(|rotation| {
+            let mut change = vec![0; max_rank];
+            let pairs = &rotation.pairs;
+
+            // p_i leaves r_i for r_{i+1}, who leaves p_{i+1} for p_i
+            for (i, (p, r)) in pairs.iter().enumerate() {
+                let (next_p, next_r) = pairs[(i + 1) % pairs.len()];
+                let p_preferences = &proposers[*p as usize].preferences;
+                let r_preferences = &responders[next_r as usize].preferences;
+
+                change[rank(p_preferences, *r) - 1] -= 1;
+                change[rank(p_preferences, next_r) - 1] += 1;
+                change[rank(r_preferences, next_p) - 1] -= 1;
+                change[rank(r_preferences, *p) - 1] += 1;
+            }
+
+            change
+        })
+        .collect()
+}
+
+// Stable matching that lexicographically maximises the number of agents with their first
+// choice, then the number with their second choice, and so on
+pub fn rank_maximal_stable_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+) -> Result<RankedMatching> {
+    let poset = RotationPoset::new(proposers, responders)?;
+
+    // Maximising the profile is the same as minimising its negation
+    let weights: Vec<_> = profile_changes(proposers, responders, &poset)
+        .into_iter()
+        .map(|change| Lexicographic(change.into_iter().map(|c| -c).collect()))
+        .collect();
+
+    let matching = poset.matching(&poset.minimum_weight_closed_set(&weights));
+    Ok(RankedMatching {
+        profile: rank_profile(proposers, responders, &matching),
+        matching,
+    })
+}
+
+// Stable matching that lexicographically minimises the number of agents with the worst
+// possible rank, then the number with the second worst, and so on
+pub fn generous_stable_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+) -> Result<RankedMatching> {
+    let poset = RotationPoset::new(proposers, responders)?;
+
+    let weights: Vec<_> = profile_changes(proposers, responders, &poset)
+        .into_iter()
+        .map(|change| Lexicographic(change.into_iter().rev().collect()))
+        .collect();
+
+    let matching = poset.matching(&poset.minimum_weight_closed_set(&weights));
+    Ok(RankedMatching {
+        profile: rank_profile(proposers, responders, &matching),
+        matching,
+    })
+}
+
+// Proposer cost minus Responder cost of the proposer-optimal matching, and how much
+// eliminating each rotation increases it. Proposers only get worse and Responders only
+// get better, so every rotation increases it
+fn imbalances(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    poset: &RotationPoset,
+) -> (i64, Vec<i64>) {
+    let start = BalancedMatching::new(proposers, responders, poset.proposer_optimal.clone());
+
+    // p_i leaves r_i for r_{i+1}, who leaves p_{i+1} for p_i
+    let increases = poset
+        .rotations
+        .iter()
+        .map(|rotation| {
+            let pairs = &rotation.pairs;
+            pairs
+                .iter()
+                .enumerate()
+                .map(|(i, (p, r))| {
+                    let (next_p, next_r) = pairs[(i + 1) % pairs.len()];
+                    let p_preferences = &proposers[*p as usize].preferences;
+                    let r_preferences = &responders[next_r as usize].preferences;
+
+                    let proposer_change =
+                        rank(p_preferences, next_r) as i64 - rank(p_preferences, *r) as i64;
+                    let responder_change =
+                        rank(r_preferences, *p) as i64 - rank(r_preferences, next_p) as i64;
+                    proposer_change - responder_change
+                })
+                .sum()
+        })
+        .collect();
+
+    (
+        start.proposer_cost as i64 - start.responder_cost as i64,
+        increases,
+    )
+}
+
+// Decide on every rotation in order, keeping track of the best closed set found so far.
+// Eliminating more rotations only increases the imbalance, so once it's positive there's
+// no point going further, and if eliminating everything left can't get it up to zero we
+// can't do better than the best so far either
+fn search(
+    index: usize,
+    imbalance: i64,
+    increases: &[i64],
+    remaining: &[i64],
+    predecessors: &[BTreeSet<usize>],
+    eliminated: &mut Vec<bool>,
+    best: &mut (i64, Vec<bool>),
+) {
+    if imbalance.abs() < best.0 {
+        *best = (imbalance.abs(), eliminated.clone());
+    }
+
+    if index == increases.len() || imbalance >= 0 || imbalance + remaining[index] <= -best.0 {
+        return;
+    }
+
+    if predecessors[index].iter().all(|j| eliminated[*j]) {
+        eliminated[index] = true;
+        search(
+            index + 1,
+            imbalance + increases[index],
+            increases,
+            remaining,
+            predecessors,
+            eliminated,
+            best,
+        );
+        eliminated[index] = false;
+    }
+
+    search(
+        index + 1,
+        imbalance,
+        increases,
+        remaining,
+        predecessors,
+        eliminated,
+        best,
+    );
+}
+
+// Stable matching minimising the difference between the Proposers' and the Responders'
+// rank sums, found by searching every closed set of rotations that could still win.
+// This can take exponential time, so larger instances should use
+// approximate_sex_equal_stable_matching instead
+pub fn sex_equal_stable_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+) -> Result<BalancedMatching> {
+    let poset = RotationPoset::new(proposers, responders)?;
+    let (start, increases) = imbalances(proposers, responders, &poset);
+
+    // remaining[i] is the total increase of every rotation from i onwards
+    let mut remaining = vec![0; increases.len() + 1];
+    for i in (0..increases.len()).rev() {
+        remaining[i] = remaining[i + 1] + increases[i];
+    }
+
+    let count = poset.rotations.len();
+    let mut best = (start.abs(), vec![false; count]);
+    search(
+        0,
+        start,
+        &increases,
+        &remaining,
+        &poset.predecessors(),
+        &mut vec![false; count],
+        &mut best,
+    );
+
+    let closed = (0..count).filter(|i| best.1[*i]).collect();
+    Ok(BalancedMatching::new(
+        proposers,
+        responders,
+        poset.matching(&closed),
+    ))
+}
+
+// Starting from the proposer-optimal matching, keep eliminating whichever exposed
+// rotation gets the imbalance closest to zero, until none of them help
+pub fn approximate_sex_equal_stable_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+) -> Result<BalancedMatching> {
+    let poset = RotationPoset::new(proposers, responders)?;
+    let predecessors = poset.predecessors();
+    let (mut imbalance, increases) = imbalances(proposers, responders, &poset);
+    let mut closed = BTreeSet::new();
+
+    loop {
+        let best = (0..poset.rotations.len())
+            .filter(|i| !closed.contains(i) && predecessors[*i].is_subset(&closed))
+            .min_by_key(|i| (imbalance + increases[*i]).abs());
+
+        match best {
+            Some(i) if (imbalance + increases[i]).abs() < imbalance.abs() => {
+                imbalance += increases[i];
+                closed.insert(i);
+            }
+            _ => break,
+        }
+    }
+
+    Ok(BalancedMatching::new(
+        proposers,
+        responders,
+        poset.matching(&closed),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::Rng;
+
+    use crate::input::validate_matching;
+    use crate::rotations::all_stable_matchings;
+
+    #[test]
+    fn egalitarian_test() {
+        let mut rng = rand::thread_rng();
+        for n in 1..40 {
+            let (proposers, responders) = crate::input::random_incomplete_input(n, &mut rng);
+
+            let matching = super::egalitarian_stable_matching(&proposers, &responders).unwrap();
+            assert_eq!(validate_matching(&proposers, &responders, &matching), true);
+
+            let best = all_stable_matchings(&proposers, &responders)
+                .unwrap()
+                .map(|m| super::rank_sum(&proposers, &responders, &m))
+                .min()
+                .unwrap();
+            assert_eq!(super::rank_sum(&proposers, &responders, &matching), best);
+        }
+    }
+
+    #[test]
+    fn minimum_cost_test() {
+        let mut rng = rand::thread_rng();
+        for n in 1..40 {
+            let (proposers, responders) = crate::input::random_input(n, &mut rng);
+            let costs: Vec<Vec<i64>> = (0..n)
+                .map(|_| (0..n).map(|_| rng.gen_range(-100, 100)).collect())
+                .collect();
+            let cost = |p: u32, r: u32| costs[p as usize][r as usize];
+            let total = |m: &std::collections::HashMap<u32, u32>| {
+                m.iter().map(|(p, r)| cost(*p, *r)).sum::<i64>()
+            };
+
+            let matching =
+                super::minimum_cost_stable_matching(&proposers, &responders, cost).unwrap();
+            assert_eq!(validate_matching(&proposers, &responders, &matching), true);
+
+            let best = all_stable_matchings(&proposers, &responders)
+                .unwrap()
+                .map(|m| total(&m))
+                .min()
+                .unwrap();
+            assert_eq!(total(&matching), best);
+        }
+    }
+
+    #[test]
+    fn minimum_regret_test() {
+        let mut rng = rand::thread_rng();
+        for n in 1..40 {
+            let (proposers, responders) = crate::input::random_input(n, &mut rng);
+
+            let result = super::minimum_regret_stable_matching(&proposers, &responders).unwrap();
+            assert_eq!(
+                validate_matching(&proposers, &responders, &result.matching),
+                true
+            );
+            assert_eq!(
+                super::regret(&proposers, &responders, &result.matching),
+                result.regret
+            );
+
+            let best = all_stable_matchings(&proposers, &responders)
+                .unwrap()
+                .map(|m| super::regret(&proposers, &responders, &m))
+                .min()
+                .unwrap();
+            assert_eq!(result.regret, best);
+        }
+    }
+
+    #[test]
+    fn rank_maximal_test() {
+        let mut rng = rand::thread_rng();
+        for n in 1..40 {
+            let (proposers, responders) = crate::input::random_input(n, &mut rng);
+
+            let result = super::rank_maximal_stable_matching(&proposers, &responders).unwrap();
+            assert_eq!(
+                validate_matching(&proposers, &responders, &result.matching),
+                true
+            );
+            assert_eq!(
+                super::rank_profile(&proposers, &responders, &result.matching),
+                result.profile
+            );
+
+            let best = all_stable_matchings(&proposers, &responders)
+                .unwrap()
+                .map(|m| super::rank_profile(&proposers, &responders, &m).total())
+                .max()
+                .unwrap();
+            assert_eq!(result.profile.total(), best);
+        }
+    }
+
+    #[test]
+    fn generous_test() {
+        let mut rng = rand::thread_rng();
+        for n in 1..40 {
+            let (proposers, responders) = crate::input::random_input(n, &mut rng);
+
+            let result = super::generous_stable_matching(&proposers, &responders).unwrap();
+            assert_eq!(
+                validate_matching(&proposers, &responders, &result.matching),
+                true
+            );
+
+            let reversed = |profile: Vec<usize>| profile.into_iter().rev().collect::<Vec<_>>();
+            let best = all_stable_matchings(&proposers, &responders)
+                .unwrap()
+                .map(|m| reversed(super::rank_profile(&proposers, &responders, &m).total()))
+                .min()
+                .unwrap();
+            assert_eq!(reversed(result.profile.total()), best);
+        }
+    }
+
+    #[test]
+    fn sex_equal_test() {
+        let mut rng = rand::thread_rng();
+        for n in 1..40 {
+            let (proposers, responders) = crate::input::random_input(n, &mut rng);
+
+            let exact = super::sex_equal_stable_matching(&proposers, &responders).unwrap();
+            let approximate =
+                super::approximate_sex_equal_stable_matching(&proposers, &responders).unwrap();
+
+            for result in [&exact, &approximate].iter() {
+                assert_eq!(
+                    validate_matching(&proposers, &responders, &result.matching),
+                    true
+                );
+                assert_eq!(
+                    result.imbalance,
+                    (result.proposer_cost as i64 - result.responder_cost as i64).abs() as usize
+                );
+            }
+
+            let imbalance = |m: &HashMap<u32, u32>| {
+                let result = super::BalancedMatching::new(&proposers, &responders, m.clone());
+                result.imbalance
+            };
+            let best = all_stable_matchings(&proposers, &responders)
+                .unwrap()
+                .map(|m| imbalance(&m))
+                .min()
+                .unwrap();
+            let proposer_optimal =
+                crate::v0::stable_matching(&proposers, &responders, crate::input::Side::Proposer)
+                    .unwrap();
+
+            assert_eq!(exact.imbalance, best);
+            assert_eq!(approximate.imbalance >= best, true);
+            assert_eq!(approximate.imbalance <= imbalance(&proposer_optimal), true);
+        }
+    }
+}
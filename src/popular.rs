@@ -0,0 +1,350 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{bail, Result};
+
+use crate::flow::FlowNetwork;
+use crate::input::{
+    validate_input, NoPopularMatching, ProposerId, ProposerInput, ResponderId, ResponderInput,
+};
+
+// Abraham, Irving, Kavitha and Mehlhorn's characterization for one-sided instances, where
+// only the Proposers have preferences and vote. Let f(p) be p's first choice, and s(p) the
+// best Responder on p's list that is nobody's first choice. A matching is popular exactly
+// when every first choice is matched, and every Proposer gets either f(p) or s(p), or is
+// left unmatched when there is no s(p). Responder preferences are ignored
+pub fn popular_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    validate_input(proposers, responders)?;
+
+    let first: Vec<Option<ResponderId>> = proposers
+        .iter()
+        .map(|p| p.preferences.last().cloned())
+        .collect();
+    let first_choices: BTreeSet<ResponderId> = first.iter().filter_map(|r| *r).collect();
+    let second: Vec<Option<ResponderId>> = proposers
+        .iter()
+        .map(|p| {
+            p.preferences
+                .iter()
+                .rev()
+                .find(|r| !first_choices.contains(r))
+                .cloned()
+        })
+        .collect();
+
+    // Find a matching where every Proposer gets f(p) or s(p). Proposers without an s(p)
+    // get a last resort of their own instead, which stands for being unmatched
+    let num_proposers = proposers.len();
+    let num_responders = responders.len();
+    let source = 0;
+    let sink = 1;
+    let proposer_node = |p: ProposerId| 2 + p as usize;
+    let responder_node = |r: ResponderId| 2 + num_proposers + r as usize;
+    let last_resort_node = |p: ProposerId| 2 + num_proposers + num_responders + p as usize;
+
+    let mut network = FlowNetwork::new(2 + 2 * num_proposers + num_responders);
+    let mut edges: Vec<(ProposerId, Option<ResponderId>, usize)> = Vec::new();
+
+    for p in proposers.iter() {
+        network.add_edge(source, proposer_node(p.id), 1);
+
+        for r in first[p.id as usize]
+            .iter()
+            .chain(second[p.id as usize].iter())
+        {
+            let edge = network.add_edge(proposer_node(p.id), responder_node(*r), 1);
+            edges.push((p.id, Some(*r), edge));
+        }
+
+        if second[p.id as usize].is_none() {
+            let edge = network.add_edge(proposer_node(p.id), last_resort_node(p.id), 1);
+            edges.push((p.id, None, edge));
+            network.add_edge(last_resort_node(p.id), sink, 1);
+        }
+    }
+
+    for r in responders.iter() {
+        network.add_edge(responder_node(r.id), sink, 1);
+    }
+
+    if network.max_flow(source, sink) < num_proposers as i64 {
+        // Proposers we can still reach from the source, and the Responders they can get,
+        // form a set of Proposers that compete for fewer Responders than there are of them
+        let reachable = network.reachable(source);
+        bail!(NoPopularMatching {
+            proposers: proposers
+                .iter()
+                .map(|p| p.id)
+                .filter(|p| reachable[proposer_node(*p)])
+                .collect(),
+            responders: responders
+                .iter()
+                .map(|r| r.id)
+                .filter(|r| reachable[responder_node(*r)])
+                .collect(),
+        });
+    }
+
+    let mut matching: HashMap<ProposerId, ResponderId> = edges
+        .iter()
+        .filter(|(_, _, edge)| network.flow(*edge) > 0)
+        .filter_map(|(p, r, _)| r.map(|r| (*p, r)))
+        .collect();
+
+    // First choices left unmatched go to one of the Proposers that want them most, who
+    // would otherwise get their s(p)
+    let matched: BTreeSet<ResponderId> = matching.values().cloned().collect();
+    for r in first_choices.iter().filter(|r| !matched.contains(r)) {
+        let p = proposers
+            .iter()
+            .find(|p| first[p.id as usize] == Some(*r))
+            .expect("first choice known to be wanted");
+        matching.insert(p.id, *r);
+    }
+
+    Ok(matching)
+}
+
+// Kavitha's two-level deferred acceptance for two-sided instances. Proposers go through
+// their lists once, and if everyone rejects them go through them again with a higher
+// priority, where Responders prefer any second round proposal to any first round one.
+// The result is a dominant matching, the largest matching that is popular when both
+// sides vote. Every two-sided instance has one
+pub fn dominant_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    validate_input(proposers, responders)?;
+
+    // Mapping from ResponderId -> ProposerId -> preference, or None if the Proposer is not
+    // acceptable
+    let mut preferences_by_proposer = vec![vec![None; proposers.len()]; responders.len()];
+    for r in responders.iter() {
+        for (index, p) in r.preferences.iter().enumerate() {
+            preferences_by_proposer[r.id as usize][*p as usize] = Some(index);
+        }
+    }
+
+    // The round every Proposer is in, and how many Responders on their list have not
+    // rejected them yet this round
+    let mut level: Vec<usize> = vec![0; proposers.len()];
+    let mut remaining: Vec<usize> = proposers.iter().map(|p| p.preferences.len()).collect();
+    let mut accepted: Vec<Option<ProposerId>> = vec![None; responders.len()];
+    let mut free: Vec<ProposerId> = proposers.iter().rev().map(|p| p.id).collect();
+
+    while let Some(p) = free.pop() {
+        let proposer = &proposers[p as usize];
+
+        if remaining[p as usize] == 0 {
+            // Go through the list a second time, unless this was the second time already
+            if level[p as usize] == 0 && !proposer.preferences.is_empty() {
+                level[p as usize] = 1;
+                remaining[p as usize] = proposer.preferences.len();
+                free.push(p);
+            }
+            continue;
+        }
+
+        let r = proposer.preferences[remaining[p as usize] - 1];
+        let priority = |q: ProposerId| {
+            preferences_by_proposer[r as usize][q as usize].map(|x| (level[q as usize], x))
+        };
+
+        let rejected = match (priority(p), accepted[r as usize]) {
+            (None, _) => p,
+            (Some(_), None) => {
+                accepted[r as usize] = Some(p);
+                continue;
+            }
+            (Some(incoming), Some(q)) => {
+                if priority(q).map_or(true, |current| incoming > current) {
+                    accepted[r as usize] = Some(p);
+                    q
+                } else {
+                    p
+                }
+            }
+        };
+
+        remaining[rejected as usize] -= 1;
+        free.push(rejected);
+    }
+
+    Ok(accepted
+        .iter()
+        .enumerate()
+        .filter_map(|(r, p)| p.map(|p| (p, r as ResponderId)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::input::{MatchingInput, NoPopularMatching, ProposerInput, ResponderInput};
+
+    // Every matching where Proposers only get Responders on their list, and Responders
+    // only get Proposers on theirs if two_sided is set
+    fn all_matchings(
+        proposers: &[ProposerInput],
+        responders: &[ResponderInput],
+        two_sided: bool,
+    ) -> Vec<HashMap<u32, u32>> {
+        fn extend(
+            index: usize,
+            proposers: &[ProposerInput],
+            responders: &[ResponderInput],
+            two_sided: bool,
+            current: &mut HashMap<u32, u32>,
+            all: &mut Vec<HashMap<u32, u32>>,
+        ) {
+            if index == proposers.len() {
+                all.push(current.clone());
+                return;
+            }
+
+            extend(index + 1, proposers, responders, two_sided, current, all);
+
+            let p = &proposers[index];
+            for r in p.preferences.iter() {
+                if !current.values().any(|x| x == r)
+                    && (!two_sided || responders[*r as usize].accepts(p.id))
+                {
+                    current.insert(p.id, *r);
+                    extend(index + 1, proposers, responders, two_sided, current, all);
+                    current.remove(&p.id);
+                }
+            }
+        }
+
+        let mut all = Vec::new();
+        extend(
+            0,
+            proposers,
+            responders,
+            two_sided,
+            &mut HashMap::new(),
+            &mut all,
+        );
+        all
+    }
+
+    // Votes for a over b, minus votes for b over a. Being unmatched is worse than being
+    // matched with anyone
+    fn votes(
+        proposers: &[ProposerInput],
+        responders: &[ResponderInput],
+        two_sided: bool,
+        a: &HashMap<u32, u32>,
+        b: &HashMap<u32, u32>,
+    ) -> i64 {
+        fn vote<I: MatchingInput>(agent: &I, a: Option<u32>, b: Option<u32>) -> i64 {
+            match (a, b) {
+                (Some(a), Some(b)) if a == b => 0,
+                (Some(a), Some(b)) if agent.prefers_more(b, a) => 1,
+                (Some(_), Some(_)) => -1,
+                (Some(_), None) => 1,
+                (None, Some(_)) => -1,
+                (None, None) => 0,
+            }
+        }
+
+        let mut total: i64 = proposers
+            .iter()
+            .map(|p| vote(p, a.get(&p.id).cloned(), b.get(&p.id).cloned()))
+            .sum();
+
+        if two_sided {
+            let invert = |m: &HashMap<u32, u32>| -> HashMap<u32, u32> {
+                m.iter().map(|(p, r)| (*r, *p)).collect()
+            };
+            let (a, b) = (invert(a), invert(b));
+            total += responders
+                .iter()
+                .map(|r| vote(r, a.get(&r.id).cloned(), b.get(&r.id).cloned()))
+                .sum::<i64>();
+        }
+
+        total
+    }
+
+    #[test]
+    fn popular_matching_test() {
+        let mut rng = rand::thread_rng();
+        let mut found = 0;
+        let mut missing = 0;
+
+        for _ in 0..50 {
+            for num_proposers in 1..6 {
+                for num_responders in 1..5 {
+                    let (proposers, responders) = crate::input::random_unbalanced_input(
+                        num_proposers,
+                        num_responders,
+                        &mut rng,
+                    );
+
+                    let all = all_matchings(&proposers, &responders, false);
+                    let popular: Vec<_> = all
+                        .iter()
+                        .filter(|a| {
+                            all.iter()
+                                .all(|b| votes(&proposers, &responders, false, a, b) >= 0)
+                        })
+                        .collect();
+
+                    match super::popular_matching(&proposers, &responders) {
+                        Ok(matching) => {
+                            assert_eq!(popular.contains(&&matching), true);
+                            found += 1;
+                        }
+                        Err(e) => {
+                            assert_eq!(popular.is_empty(), true);
+                            let e = e.downcast::<NoPopularMatching>().unwrap();
+                            assert_eq!(e.proposers.len() > e.responders.len(), true);
+                            missing += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        assert_eq!(found > 0 && missing > 0, true);
+    }
+
+    #[test]
+    fn dominant_matching_test() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..20 {
+            for n in 1..5 {
+                let (proposers, responders) = crate::input::random_incomplete_input(n, &mut rng);
+
+                let all = all_matchings(&proposers, &responders, true);
+                let popular: Vec<_> = all
+                    .iter()
+                    .filter(|a| {
+                        all.iter()
+                            .all(|b| votes(&proposers, &responders, true, a, b) >= 0)
+                    })
+                    .collect();
+
+                // The dominant matching is popular, and no popular matching is larger
+                let matching = super::dominant_matching(&proposers, &responders).unwrap();
+                assert_eq!(popular.contains(&&matching), true);
+                for other in popular.iter() {
+                    assert_eq!(matching.len() >= other.len(), true);
+                }
+
+                // Stable matchings are popular too, but can be smaller
+                let stable = crate::v0::stable_matching(
+                    &proposers,
+                    &responders,
+                    crate::input::Side::Proposer,
+                )
+                .unwrap();
+                assert_eq!(popular.contains(&&stable), true);
+            }
+        }
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{bail, Result};
+
+use crate::input::{RoommateId, RoommateInput};
+
+#[derive(Debug)]
+pub enum RoommatesOutcome {
+    // Every matched pair shows up in both directions
+    Matching(HashMap<RoommateId, RoommateId>),
+    // Eliminating the rotation made up of odd_party left exhausted with nobody
+    // to be matched with, which proves that no stable matching exists
+    NoStableMatching {
+        odd_party: Vec<RoommateId>,
+        exhausted: RoommateId,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StablePartition {
+    // Every Roommate shows up in exactly one cycle, followed by its successor. Pairs are
+    // cycles of length two, and Roommates left on their own are cycles of length one
+    pub cycles: Vec<Vec<RoommateId>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Exclusion {
+    // Everyone this Roommate finds acceptable is better off elsewhere
+    NoAcceptablePartner,
+    // This Roommate was dropped from an odd cycle, and the rest of the cycle was paired up
+    OddCycle(Vec<RoommateId>),
+}
+
+#[derive(Debug)]
+pub struct MaximumStableMatching {
+    // Every matched pair shows up in both directions
+    pub matching: HashMap<RoommateId, RoommateId>,
+    pub excluded: HashMap<RoommateId, Exclusion>,
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    // Reduced preference lists ordered by *descending* preference, so that the first
+    // entry is who each Roommate currently proposes to and the last entry is who
+    // they currently hold a proposal from
+    lists: Vec<Vec<RoommateId>>,
+}
+
+impl Table {
+    fn new(roommates: &[RoommateInput]) -> Result<Self> {
+        let n = roommates.len();
+
+        // Mapping from (Roommate, other) -> preference, or None if other is not acceptable
+        // Relies on RoommateIds being in the domain [0, n)
+        let mut ranks: Vec<Vec<Option<usize>>> = vec![vec![None; n]; n];
+
+        for r in roommates.iter() {
+            if r.id as usize >= n {
+                bail!(
+                    "received invalid roommate input id: {} preferences: {:?}",
+                    r.id,
+                    r.preferences
+                );
+            }
+
+            for (index, other) in r.preferences.iter().enumerate() {
+                if *other as usize >= n
+                    || *other == r.id
+                    || ranks[r.id as usize][*other as usize].is_some()
+                {
+                    bail!(
+                        "received invalid preferences in roommate: {} preferences: {:?}",
+                        r.id,
+                        r.preferences
+                    );
+                }
+
+                ranks[r.id as usize][*other as usize] = Some(index);
+            }
+        }
+
+        // Only keep the pairs that are mutually acceptable
+        let lists = (0..n)
+            .map(|a| {
+                let mut list: Vec<_> = (0..n)
+                    .filter(|b| ranks[a][*b].is_some() && ranks[*b][a].is_some())
+                    .collect();
+                list.sort_by(|b, c| ranks[a][*c].cmp(&ranks[a][*b]));
+                list.into_iter().map(|b| b as RoommateId).collect()
+            })
+            .collect();
+
+        Ok(Table { lists })
+    }
+
+    fn delete(&mut self, a: RoommateId, b: RoommateId) {
+        self.lists[a as usize].retain(|x| *x != b);
+        self.lists[b as usize].retain(|x| *x != a);
+    }
+
+    // Delete every pair (a, c) where a likes c less than b
+    fn delete_successors(&mut self, a: RoommateId, b: RoommateId) {
+        let list = &self.lists[a as usize];
+        let successors: Vec<_> = match list.iter().position(|x| *x == b) {
+            Some(index) => list[index + 1..].to_vec(),
+            None => Vec::new(),
+        };
+
+        for c in successors.into_iter() {
+            self.delete(a, c);
+        }
+    }
+
+    fn phase_one(&mut self) {
+        let n = self.lists.len();
+        let mut free: Vec<RoommateId> = (0..n as RoommateId).rev().collect();
+        let mut held: Vec<Option<RoommateId>> = vec![None; n];
+
+        // Every free Roommate proposes to the first entry on its list. Anyone still on a
+        // list is preferred to the proposal currently held, so every proposal is held,
+        // and the previously held proposal is rejected
+        while let Some(x) = free.pop() {
+            let y = match self.lists[x as usize].first() {
+                Some(y) => *y,
+                // Roommates rejected by everyone they find acceptable stay unmatched
+                None => continue,
+            };
+
+            if let Some(previous) = held[y as usize].replace(x) {
+                free.push(previous);
+            }
+
+            self.delete_successors(y, x);
+        }
+    }
+
+    // Find a rotation (x_0, y_0), ..., (x_{r-1}, y_{r-1}) where y_i is first on x_i's
+    // list, and x_{i+1} is last on the list of the second entry on x_i's list
+    fn find_rotation(&self, start: RoommateId) -> Vec<(RoommateId, RoommateId)> {
+        let mut sequence = vec![start];
+        let mut positions: HashMap<RoommateId, usize> = HashMap::new();
+        positions.insert(start, 0);
+
+        loop {
+            let p = *sequence.last().expect("sequence known to be non-empty");
+            let second = self.lists[p as usize][1];
+            let next = *self.lists[second as usize]
+                .last()
+                .expect("list known to be non-empty");
+
+            if let Some(index) = positions.get(&next) {
+                return sequence[*index..]
+                    .iter()
+                    .map(|x| (*x, self.lists[*x as usize][0]))
+                    .collect();
+            }
+
+            positions.insert(next, sequence.len());
+            sequence.push(next);
+        }
+    }
+
+    fn eliminate(&mut self, rotation: &[(RoommateId, RoommateId)]) {
+        // Every x_i gets rejected by y_i and moves on to the second entry on its list,
+        // y_{i+1}, who then rejects everyone worse than x_i
+        let seconds: Vec<_> = rotation
+            .iter()
+            .map(|(x, _)| self.lists[*x as usize][1])
+            .collect();
+
+        for ((x, _), second) in rotation.iter().zip(seconds.into_iter()) {
+            self.delete_successors(second, *x);
+        }
+    }
+}
+
+impl StablePartition {
+    // Every stable partition has the same odd cycles, and dropping one Roommate from each
+    // of them leaves an instance that has a stable matching. The matching we get by
+    // pairing up the rest of every cycle is as large as a stable matching can get
+    pub fn maximum_stable_matching(&self) -> MaximumStableMatching {
+        let mut matching = HashMap::new();
+        let mut excluded = HashMap::new();
+
+        for cycle in self.cycles.iter() {
+            if cycle.len() == 1 {
+                excluded.insert(cycle[0], Exclusion::NoAcceptablePartner);
+                continue;
+            }
+
+            // Drop the first Roommate of odd cycles, and pair up everyone else with
+            // their successor
+            let start = if cycle.len() % 2 == 1 {
+                excluded.insert(cycle[0], Exclusion::OddCycle(cycle.clone()));
+                1
+            } else {
+                0
+            };
+
+            for pair in cycle[start..].chunks(2) {
+                matching.insert(pair[0], pair[1]);
+                matching.insert(pair[1], pair[0]);
+            }
+        }
+
+        MaximumStableMatching { matching, excluded }
+    }
+}
+
+pub fn stable_matching(roommates: &[RoommateInput]) -> Result<RoommatesOutcome> {
+    let mut table = Table::new(roommates)?;
+    let n = table.lists.len();
+
+    table.phase_one();
+
+    // Eliminate rotations until every list has at most one entry left
+    while let Some(start) = (0..n).find(|a| table.lists[*a].len() > 1) {
+        let rotation = table.find_rotation(start as RoommateId);
+        let matched: Vec<bool> = table.lists.iter().map(|l| !l.is_empty()).collect();
+
+        table.eliminate(&rotation);
+
+        if let Some(exhausted) = (0..n).find(|a| matched[*a] && table.lists[*a].is_empty()) {
+            return Ok(RoommatesOutcome::NoStableMatching {
+                odd_party: rotation.iter().map(|(x, _)| *x).collect(),
+                exhausted: exhausted as RoommateId,
+            });
+        }
+    }
+
+    Ok(RoommatesOutcome::Matching(
+        (0..n)
+            .filter(|a| table.lists[*a].len() == 1)
+            .map(|a| (a as RoommateId, table.lists[a][0]))
+            .collect(),
+    ))
+}
+
+// Tan's algorithm. Runs phase two like stable_matching, but whenever a rotation turns out
+// to be an odd party its members get set aside as an odd cycle, with every Roommate
+// followed by the first entry on its list
+pub fn stable_partition(roommates: &[RoommateInput]) -> Result<StablePartition> {
+    let mut table = Table::new(roommates)?;
+    let n = table.lists.len();
+    let mut cycles = Vec::new();
+    let mut odd: Vec<bool> = vec![false; n];
+
+    table.phase_one();
+
+    while let Some(start) = (0..n).find(|a| !odd[*a] && table.lists[*a].len() > 1) {
+        let rotation = table.find_rotation(start as RoommateId);
+
+        // A rotation (x_0, y_0), ..., (x_{r-1}, y_{r-1}) is an odd party when r is odd and
+        // every y_i is x_{i+(r-1)/2}. Eliminating it would leave its members with nobody
+        let r = rotation.len();
+        let odd_party =
+            r % 2 == 1 && (0..r).all(|i| rotation[i].1 == rotation[(i + (r - 1) / 2) % r].0);
+
+        if !odd_party {
+            table.eliminate(&rotation);
+            continue;
+        }
+
+        let xs: BTreeSet<_> = rotation.iter().map(|(x, _)| *x).collect();
+        for x in xs.iter() {
+            odd[*x as usize] = true;
+        }
+
+        // Every member is followed by the first entry on its list
+        let successors: HashMap<_, _> = rotation.iter().cloned().collect();
+        let predecessors: HashMap<_, _> = rotation.iter().map(|(x, y)| (*y, *x)).collect();
+
+        // Everyone outside of the odd party that is still on one of its members' lists
+        // rejects that member along with everyone worse. Members are then left with
+        // only their successor and predecessor in the cycle
+        for x in xs.iter() {
+            let others: Vec<_> = table.lists[*x as usize]
+                .iter()
+                .filter(|z| **z != successors[x] && **z != predecessors[x])
+                .cloned()
+                .collect();
+
+            for z in others.into_iter() {
+                if !xs.contains(&z) {
+                    table.delete_successors(z, *x);
+                }
+                table.delete(z, *x);
+            }
+        }
+
+        let mut cycle = vec![rotation[0].0];
+        let mut next = successors[&rotation[0].0];
+        while next != cycle[0] {
+            cycle.push(next);
+            next = successors[&next];
+        }
+        cycles.push(cycle);
+
+        // Outsiders may have lost the proposal they held, so the table needs to go
+        // through phase one again
+        table.phase_one();
+    }
+
+    for a in 0..n {
+        if odd[a] {
+            continue;
+        }
+
+        match table.lists[a].first() {
+            Some(b) if a < *b as usize => cycles.push(vec![a as RoommateId, *b]),
+            Some(_) => (),
+            None => cycles.push(vec![a as RoommateId]),
+        }
+    }
+
+    Ok(StablePartition { cycles })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::Rng;
+
+    use super::{Exclusion, RoommatesOutcome};
+    use crate::input::{validate_roommates_matching, validate_stable_partition, RoommateInput};
+
+    // Every matching of mutually acceptable pairs, found by brute force
+    fn all_matchings(roommates: &[RoommateInput]) -> Vec<HashMap<u32, u32>> {
+        fn extend(
+            index: usize,
+            roommates: &[RoommateInput],
+            current: &mut HashMap<u32, u32>,
+            all: &mut Vec<HashMap<u32, u32>>,
+        ) {
+            if index == roommates.len() {
+                all.push(current.clone());
+                return;
+            }
+
+            let a = roommates[index].id;
+            if current.contains_key(&a) {
+                extend(index + 1, roommates, current, all);
+                return;
+            }
+
+            extend(index + 1, roommates, current, all);
+
+            for b in roommates[index].preferences.iter() {
+                if !current.contains_key(b) && roommates[*b as usize].preferences.contains(&a) {
+                    current.insert(a, *b);
+                    current.insert(*b, a);
+                    extend(index + 1, roommates, current, all);
+                    current.remove(&a);
+                    current.remove(b);
+                }
+            }
+        }
+
+        let mut all = Vec::new();
+        extend(0, roommates, &mut HashMap::new(), &mut all);
+        all
+    }
+
+    #[test]
+    fn basic_roommates_test() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            for n in 1..9 {
+                let mut roommates = crate::input::random_roommates_input(n, &mut rng);
+
+                // Make some of the lists incomplete
+                if rng.gen_bool(0.5) {
+                    for r in roommates.iter_mut() {
+                        r.preferences.retain(|_| rng.gen_bool(0.8));
+                    }
+                }
+
+                let exists = all_matchings(&roommates)
+                    .iter()
+                    .any(|m| validate_roommates_matching(&roommates, m));
+
+                match super::stable_matching(&roommates).unwrap() {
+                    RoommatesOutcome::Matching(matching) => {
+                        assert_eq!(validate_roommates_matching(&roommates, &matching), true);
+                    }
+                    RoommatesOutcome::NoStableMatching { .. } => assert_eq!(exists, false),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stable_partition_test() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            for n in 1..9 {
+                let mut roommates = crate::input::random_roommates_input(n, &mut rng);
+
+                if rng.gen_bool(0.5) {
+                    for r in roommates.iter_mut() {
+                        r.preferences.retain(|_| rng.gen_bool(0.8));
+                    }
+                }
+
+                let exists = all_matchings(&roommates)
+                    .iter()
+                    .any(|m| validate_roommates_matching(&roommates, m));
+
+                let partition = super::stable_partition(&roommates).unwrap();
+                assert_eq!(
+                    validate_stable_partition(&roommates, &partition.cycles),
+                    true
+                );
+
+                // Odd cycles show up exactly when there is no stable matching
+                let odd = partition.cycles.iter().filter(|c| c.len() > 2).count();
+                assert_eq!(exists, odd == 0);
+
+                // Once the excluded Roommates are gone, the matching has to be stable
+                let result = partition.maximum_stable_matching();
+                assert_eq!(
+                    result
+                        .excluded
+                        .values()
+                        .filter(|e| **e != Exclusion::NoAcceptablePartner)
+                        .count(),
+                    odd
+                );
+                assert_eq!(result.matching.len() + result.excluded.len(), n as usize);
+
+                for r in roommates.iter_mut() {
+                    if result.excluded.contains_key(&r.id) {
+                        r.preferences.clear();
+                    }
+                    r.preferences.retain(|x| !result.excluded.contains_key(x));
+                }
+                assert_eq!(
+                    validate_roommates_matching(&roommates, &result.matching),
+                    true
+                );
+            }
+        }
+    }
+}
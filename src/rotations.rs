@@ -0,0 +1,863 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{bail, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::flow::{Capacity, FlowNetwork};
+use crate::input::{
+    validate_matching, MatchingInput, ProposerId, ProposerInput, ResponderId, ResponderInput, Side,
+};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Rotation {
+    // Pairs (p_0, r_0), ..., (p_{k-1}, r_{k-1}) from a stable matching. Eliminating the
+    // rotation moves every Proposer p_i from r_i on to r_{i+1}
+    pub pairs: Vec<(ProposerId, ResponderId)>,
+}
+
+#[derive(Debug)]
+pub struct RotationPoset {
+    pub proposer_optimal: HashMap<ProposerId, ResponderId>,
+    // Every rotation, in an order they can be eliminated in starting from the
+    // proposer-optimal matching
+    pub rotations: Vec<Rotation>,
+    // rotations[i] has to be eliminated before every rotation in successors[i]. Only
+    // the edges of the Hasse diagram are kept, so the relation is the transitive closure
+    pub successors: Vec<BTreeSet<usize>>,
+}
+
+impl RotationPoset {
+    pub fn new(proposers: &[ProposerInput], responders: &[ResponderInput]) -> Result<Self> {
+        let proposer_optimal = crate::v0::stable_matching(proposers, responders, Side::Proposer)?;
+        let responder_optimal = crate::v0::stable_matching(proposers, responders, Side::Responder)?;
+
+        // Mapping from (Responder, Proposer) -> preference, or None if the Proposer is not
+        // acceptable. Relies on ids being in the domain [0, n)
+        let mut ranks: Vec<Vec<Option<usize>>> =
+            vec![vec![None; proposers.len()]; responders.len()];
+        for r in responders.iter() {
+            for (index, p) in r.preferences.iter().enumerate() {
+                ranks[r.id as usize][*p as usize] = Some(index);
+            }
+        }
+        let prefers_more = |r: ResponderId, assigned: ProposerId, alternative: ProposerId| {
+            ranks[r as usize][alternative as usize] > ranks[r as usize][assigned as usize]
+        };
+
+        let mut matching = proposer_optimal.clone();
+        let mut partners: HashMap<ResponderId, ProposerId> =
+            matching.iter().map(|(p, r)| (*r, *p)).collect();
+
+        // Every partner each Responder has had, along with the rotation that matched them
+        // together or None for the proposer-optimal matching
+        let mut history: HashMap<ResponderId, Vec<(ProposerId, Option<usize>)>> = partners
+            .iter()
+            .map(|(r, p)| (*r, vec![(*p, None)]))
+            .collect();
+        let mut produced_by: HashMap<(ProposerId, ResponderId), usize> = HashMap::new();
+
+        let mut rotations = Vec::new();
+        let mut edges: Vec<BTreeSet<usize>> = Vec::new();
+
+        // Proposers prefer responders that come later in their preferences, so the list
+        // of alternatives is walked backwards starting from the current partner
+        let alternative = |p: ProposerId,
+                           matching: &HashMap<ProposerId, ResponderId>,
+                           partners: &HashMap<ResponderId, ProposerId>| {
+            let preferences = &proposers[p as usize].preferences;
+            let position = preferences
+                .iter()
+                .position(|r| *r == matching[&p])
+                .expect("partner known to be acceptable");
+
+            preferences[..position]
+                .iter()
+                .rev()
+                .cloned()
+                .find(|r| match partners.get(r) {
+                    Some(assigned) => prefers_more(*r, *assigned, p),
+                    None => false,
+                })
+                .expect("proposers that can still move known to have an alternative")
+        };
+
+        // Until we reach the responder-optimal matching there is always at least one
+        // exposed rotation. Every Proposer that has not reached its worst partner points
+        // to the Proposer matched with its next alternative, and following them has to
+        // end up in a cycle
+        while let Some(start) = proposers
+            .iter()
+            .map(|p| p.id)
+            .find(|p| matching.get(p) != responder_optimal.get(p))
+        {
+            let mut sequence = vec![start];
+            let mut positions: HashMap<ProposerId, usize> = HashMap::new();
+            positions.insert(start, 0);
+
+            let cycle = loop {
+                let p = *sequence.last().expect("sequence known to be non-empty");
+                let next = partners[&alternative(p, &matching, &partners)];
+
+                if let Some(index) = positions.get(&next) {
+                    break sequence[*index..].to_vec();
+                }
+
+                positions.insert(next, sequence.len());
+                sequence.push(next);
+            };
+
+            let index = rotations.len();
+            let pairs: Vec<_> = cycle.iter().map(|p| (*p, matching[p])).collect();
+            let mut predecessors = BTreeSet::new();
+
+            for (i, (p, r)) in pairs.iter().enumerate() {
+                let next = pairs[(i + 1) % pairs.len()].1;
+
+                // Whichever rotation matched p with r has to come first
+                if let Some(rotation) = produced_by.get(&(*p, *r)) {
+                    predecessors.insert(*rotation);
+                }
+
+                // Responders p skips over already have someone better than p. Whichever
+                // rotation got them from someone worse than p to someone better has to
+                // come first
+                let preferences = &proposers[*p as usize].preferences;
+                let from = preferences
+                    .iter()
+                    .position(|x| x == r)
+                    .expect("known to exist");
+                let to = preferences
+                    .iter()
+                    .position(|x| *x == next)
+                    .expect("known to exist");
+
+                for skipped in preferences[to + 1..from].iter() {
+                    if ranks[*skipped as usize][*p as usize].is_none() {
+                        continue;
+                    }
+
+                    let passed = history[skipped]
+                        .iter()
+                        .find(|(assigned, _)| prefers_more(*skipped, *p, *assigned));
+
+                    if let Some((_, Some(rotation))) = passed {
+                        predecessors.insert(*rotation);
+                    }
+                }
+            }
+
+            // Eliminate the rotation
+            for (i, (p, _)) in pairs.iter().enumerate() {
+                let next = pairs[(i + 1) % pairs.len()].1;
+                matching.insert(*p, next);
+                partners.insert(next, *p);
+                history
+                    .get_mut(&next)
+                    .expect("responder known to be matched")
+                    .push((*p, Some(index)));
+                produced_by.insert((*p, next), index);
+            }
+
+            for predecessor in predecessors.into_iter() {
+                edges[predecessor].insert(index);
+            }
+            edges.push(BTreeSet::new());
+            rotations.push(Rotation { pairs });
+        }
+
+        // Every edge goes from an earlier rotation to a later one, so we can find
+        // everything reachable from each rotation by going backwards
+        let mut reachable: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); rotations.len()];
+        for i in (0..rotations.len()).rev() {
+            for j in edges[i].iter() {
+                let descendants = reachable[*j].clone();
+                reachable[i].insert(*j);
+                reachable[i].extend(descendants);
+            }
+        }
+
+        // Only keep the edges that aren't implied by some longer path
+        let successors = edges
+            .iter()
+            .map(|out| {
+                out.iter()
+                    .filter(|j| !out.iter().any(|k| reachable[*k].contains(j)))
+                    .cloned()
+                    .collect()
+            })
+            .collect();
+
+        Ok(RotationPoset {
+            proposer_optimal,
+            rotations,
+            successors,
+        })
+    }
+
+    // Immediate predecessors of every rotation in the Hasse diagram
+    pub fn predecessors(&self) -> Vec<BTreeSet<usize>> {
+        let mut predecessors = vec![BTreeSet::new(); self.rotations.len()];
+        for (index, successors) in self.successors.iter().enumerate() {
+            for successor in successors.iter() {
+                predecessors[*successor].insert(index);
+            }
+        }
+
+        predecessors
+    }
+
+    pub fn precedes(&self, a: usize, b: usize) -> bool {
+        let mut stack = vec![a];
+        let mut visited = BTreeSet::new();
+
+        while let Some(x) = stack.pop() {
+            for y in self.successors[x].iter() {
+                if *y == b {
+                    return true;
+                }
+
+                if visited.insert(*y) {
+                    stack.push(*y);
+                }
+            }
+        }
+
+        false
+    }
+
+    // The stable matching we get from eliminating a closed set of rotations, where
+    // every rotation that precedes a rotation in the set is also in the set
+    pub fn matching(&self, closed: &BTreeSet<usize>) -> HashMap<ProposerId, ResponderId> {
+        let mut matching = self.proposer_optimal.clone();
+
+        // Rotations are kept in an order they can be eliminated in
+        for index in closed.iter() {
+            let pairs = &self.rotations[*index].pairs;
+            for (i, (p, _)) in pairs.iter().enumerate() {
+                matching.insert(*p, pairs[(i + 1) % pairs.len()].1);
+            }
+        }
+
+        matching
+    }
+
+    // Closed set of rotations with the smallest total weight, where weights[i] is how
+    // much eliminating rotations[i] changes whatever we are minimising. Found with a
+    // minimum cut: rotations that would lower the total hang off the source, the others
+    // lead into the sink, and nothing can be cut between a rotation and its predecessors
+    pub fn minimum_weight_closed_set<C: Capacity>(&self, weights: &[C]) -> BTreeSet<usize> {
+        let count = self.rotations.len();
+        let (source, sink) = (count, count + 1);
+
+        // Edges between rotations must never be cut, so give them more capacity than
+        // every other edge put together
+        let infinite = weights.iter().fold(C::zero(), |total, w| {
+            if *w < C::zero() {
+                total + C::zero() - w.clone() - w.clone()
+            } else {
+                total + w.clone() + w.clone()
+            }
+        });
+
+        let mut network = FlowNetwork::new(count + 2);
+        for (index, weight) in weights.iter().enumerate() {
+            if *weight < C::zero() {
+                network.add_edge(source, index, C::zero() - weight.clone());
+            } else if *weight > C::zero() {
+                network.add_edge(index, sink, weight.clone());
+            }
+
+            for successor in self.successors[index].iter() {
+                network.add_edge(*successor, index, infinite.clone());
+            }
+        }
+
+        network.max_flow(source, sink);
+        let reachable = network.reachable(source);
+
+        (0..count).filter(|i| reachable[*i]).collect()
+    }
+
+    // Graphviz representation of the Hasse diagram, with every rotation labelled
+    // by its pairs
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph rotations {\n");
+
+        for (index, rotation) in self.rotations.iter().enumerate() {
+            let label: Vec<_> = rotation
+                .pairs
+                .iter()
+                .map(|(p, r)| format!("(p{}, r{})", p, r))
+                .collect();
+            dot.push_str(&format!("    {} [label=\"{}\"];\n", index, label.join(" ")));
+        }
+
+        for (index, successors) in self.successors.iter().enumerate() {
+            for successor in successors.iter() {
+                dot.push_str(&format!("    {} -> {};\n", index, successor));
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+// Lazily walks every closed set of rotations, and with it every stable matching.
+// Rotations are decided on one at a time in the order they are stored in, which
+// means every rotation's predecessors are decided before it is, and every partial
+// set of decisions can be completed by leaving the remaining rotations out
+pub struct StableMatchings {
+    poset: RotationPoset,
+    predecessors: Vec<BTreeSet<usize>>,
+    // Whether each rotation is eliminated in the current closed set, or None before
+    // the first stable matching is produced
+    eliminated: Option<Vec<bool>>,
+}
+
+// Move on to the next closed set in the order StableMatchings walks them: find the last
+// rotation we left out but could have eliminated, and eliminate it instead while leaving
+// out every rotation after it. Returns false once every closed set has been visited
+fn next_closed_set(eliminated: &mut [bool], predecessors: &[BTreeSet<usize>]) -> bool {
+    let index = (0..eliminated.len())
+        .rev()
+        .find(|i| !eliminated[*i] && predecessors[*i].iter().all(|j| eliminated[*j]));
+
+    match index {
+        Some(index) => {
+            eliminated[index] = true;
+            for later in eliminated[index + 1..].iter_mut() {
+                *later = false;
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+impl Iterator for StableMatchings {
+    type Item = HashMap<ProposerId, ResponderId>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let count = self.poset.rotations.len();
+
+        let eliminated = match self.eliminated.as_mut() {
+            None => {
+                // Start with the proposer-optimal matching
+                self.eliminated = Some(vec![false; count]);
+                self.eliminated.as_mut().expect("known to exist")
+            }
+            Some(eliminated) => {
+                if !next_closed_set(eliminated, &self.predecessors) {
+                    return None;
+                }
+                eliminated
+            }
+        };
+
+        let closed = (0..count).filter(|i| eliminated[*i]).collect();
+        Some(self.poset.matching(&closed))
+    }
+}
+
+pub fn all_stable_matchings(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+) -> Result<StableMatchings> {
+    let poset = RotationPoset::new(proposers, responders)?;
+
+    Ok(StableMatchings {
+        predecessors: poset.predecessors(),
+        poset,
+        eliminated: None,
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StableMatchingCount {
+    Exact(u64),
+    // There were more stable matchings than the limit, so this is an unbiased estimate
+    Estimate(f64),
+}
+
+// Number of samples used to estimate counts that go over the limit
+const ESTIMATE_SAMPLES: usize = 1000;
+
+impl RotationPoset {
+    // Counts closed sets one at a time, giving up once there are more than limit
+    pub fn count_closed_sets(&self, limit: u64) -> Option<u64> {
+        let predecessors = self.predecessors();
+        let mut eliminated = vec![false; self.rotations.len()];
+        let mut count = 0;
+
+        loop {
+            count += 1;
+            if count > limit {
+                return None;
+            }
+
+            if !next_closed_set(&mut eliminated, &predecessors) {
+                return Some(count);
+            }
+        }
+    }
+
+    // Knuth's estimator. Closed sets are the leaves of a tree where every rotation is
+    // either eliminated or left out in order, and a rotation can only be eliminated if
+    // all its predecessors were. Walking down a random path and multiplying the number
+    // of choices along the way gives an unbiased estimate of the number of leaves
+    pub fn estimate_closed_sets(&self, samples: usize, rng: &mut StdRng) -> f64 {
+        let predecessors = self.predecessors();
+        let mut total = 0.0;
+
+        for _ in 0..samples {
+            let mut eliminated = vec![false; self.rotations.len()];
+            let mut estimate = 1.0;
+
+            for index in 0..eliminated.len() {
+                if predecessors[index].iter().all(|j| eliminated[*j]) {
+                    estimate *= 2.0;
+                    eliminated[index] = rng.gen_bool(0.5);
+                }
+            }
+
+            total += estimate;
+        }
+
+        total / samples as f64
+    }
+}
+
+// Count stable matchings exactly as long as there are at most limit of them, and
+// estimate the count otherwise
+pub fn count_stable_matchings(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    limit: u64,
+    seed: u64,
+) -> Result<StableMatchingCount> {
+    let poset = RotationPoset::new(proposers, responders)?;
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    Ok(match poset.count_closed_sets(limit) {
+        Some(count) => StableMatchingCount::Exact(count),
+        None => {
+            StableMatchingCount::Estimate(poset.estimate_closed_sets(ESTIMATE_SAMPLES, &mut rng))
+        }
+    })
+}
+
+// Instances with at most this many stable matchings are sampled exactly
+const EXACT_SAMPLING_LIMIT: u64 = 100_000;
+
+impl RotationPoset {
+    // Uniformly random closed set, picked by counting them all and walking to a random one
+    fn exact_sample(&self, count: u64, rng: &mut StdRng) -> BTreeSet<usize> {
+        let predecessors = self.predecessors();
+        let mut eliminated = vec![false; self.rotations.len()];
+
+        for _ in 0..rng.gen_range(0, count) {
+            next_closed_set(&mut eliminated, &predecessors);
+        }
+
+        (0..eliminated.len()).filter(|i| eliminated[*i]).collect()
+    }
+
+    // How long the Markov chain gets to mix
+    fn mixing_steps(&self) -> usize {
+        100 * self.rotations.len() * self.rotations.len()
+    }
+
+    // Markov chain over closed sets. Every step picks a random rotation, and eliminates
+    // it if all its predecessors are eliminated, or undoes it if none of its successors
+    // are. Half the steps do nothing, so the chain is symmetric and aperiodic and its
+    // stationary distribution is uniform
+    fn markov_chain_sample(&self, steps: usize, rng: &mut StdRng) -> BTreeSet<usize> {
+        let predecessors = self.predecessors();
+        let mut closed = BTreeSet::new();
+
+        if self.rotations.is_empty() {
+            return closed;
+        }
+
+        for _ in 0..steps {
+            if rng.gen_bool(0.5) {
+                continue;
+            }
+
+            let rotation = rng.gen_range(0, self.rotations.len());
+            if closed.contains(&rotation) {
+                if self.successors[rotation].is_disjoint(&closed) {
+                    closed.remove(&rotation);
+                }
+            } else if predecessors[rotation].is_subset(&closed) {
+                closed.insert(rotation);
+            }
+        }
+
+        closed
+    }
+}
+
+// Draws a stable matching uniformly at random when there are few enough of them to
+// count, and close to uniformly otherwise
+pub fn sample_stable_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    seed: u64,
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    let poset = RotationPoset::new(proposers, responders)?;
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let closed = match poset.count_closed_sets(EXACT_SAMPLING_LIMIT) {
+        Some(count) => poset.exact_sample(count, &mut rng),
+        None => poset.markov_chain_sample(poset.mixing_steps(), &mut rng),
+    };
+
+    Ok(poset.matching(&closed))
+}
+
+// Give every Proposer whichever of its partners in a and b it likes more, or less
+fn combine(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    a: &HashMap<ProposerId, ResponderId>,
+    b: &HashMap<ProposerId, ResponderId>,
+    better: bool,
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    if !validate_matching(proposers, responders, a) || !validate_matching(proposers, responders, b)
+    {
+        bail!("received a matching that is not stable");
+    }
+
+    // Stability is only checked for the agents we were given, so anyone else in either
+    // matching has to be rejected separately
+    let by_id: HashMap<_, _> = proposers.iter().map(|p| (p.id, p)).collect();
+    for (p, r) in a.iter().chain(b.iter()) {
+        if !by_id.contains_key(p) || !responders.iter().any(|x| x.id == *r) {
+            bail!("received a matching with unknown pair: ({}, {})", p, r);
+        }
+    }
+
+    // Stable matchings all match the same Proposers
+    if a.len() != b.len() || a.keys().any(|p| !b.contains_key(p)) {
+        bail!("received matchings with different Proposers matched");
+    }
+
+    let matching = a
+        .iter()
+        .map(|(p, r)| {
+            let other = match b.get(p) {
+                Some(other) => *other,
+                None => bail!("proposer: {} is missing from the second matching", p),
+            };
+            let proposer = match by_id.get(p) {
+                Some(proposer) => proposer,
+                None => bail!("received a matching with unknown proposer: {}", p),
+            };
+
+            if proposer.prefers_more(*r, other) == better {
+                Ok((*p, other))
+            } else {
+                Ok((*p, *r))
+            }
+        })
+        .collect::<Result<_>>()?;
+
+    if !validate_matching(proposers, responders, &matching) {
+        bail!("combined matching is not stable: {:?}", matching);
+    }
+
+    Ok(matching)
+}
+
+// Every Proposer gets the better of its partners in a and b, and every Responder the worse
+pub fn meet(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    a: &HashMap<ProposerId, ResponderId>,
+    b: &HashMap<ProposerId, ResponderId>,
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    combine(proposers, responders, a, b, true)
+}
+
+// Every Proposer gets the worse of its partners in a and b, and every Responder the better
+pub fn join(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    a: &HashMap<ProposerId, ResponderId>,
+    b: &HashMap<ProposerId, ResponderId>,
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    combine(proposers, responders, a, b, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+
+    use rand::SeedableRng;
+
+    use crate::input::{validate_matching, ProposerInput, ResponderInput};
+
+    // Every stable matching, found by brute force
+    fn brute_force_matchings(
+        proposers: &[ProposerInput],
+        responders: &[ResponderInput],
+    ) -> Vec<HashMap<u32, u32>> {
+        fn extend(
+            index: usize,
+            proposers: &[ProposerInput],
+            responders: &[ResponderInput],
+            current: &mut HashMap<u32, u32>,
+            all: &mut Vec<HashMap<u32, u32>>,
+        ) {
+            if index == proposers.len() {
+                if validate_matching(proposers, responders, current) {
+                    all.push(current.clone());
+                }
+                return;
+            }
+
+            extend(index + 1, proposers, responders, current, all);
+
+            for r in proposers[index].preferences.iter() {
+                if !current.values().any(|x| x == r) {
+                    current.insert(proposers[index].id, *r);
+                    extend(index + 1, proposers, responders, current, all);
+                    current.remove(&proposers[index].id);
+                }
+            }
+        }
+
+        let mut all = Vec::new();
+        extend(0, proposers, responders, &mut HashMap::new(), &mut all);
+        all
+    }
+
+    #[test]
+    fn rotation_poset_test() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..50 {
+            for n in 1..7 {
+                for incomplete in [false, true].iter() {
+                    let (proposers, responders) = if *incomplete {
+                        crate::input::random_incomplete_input(n, &mut rng)
+                    } else {
+                        crate::input::random_input(n, &mut rng)
+                    };
+
+                    let poset = super::RotationPoset::new(&proposers, &responders).unwrap();
+                    let all = brute_force_matchings(&proposers, &responders);
+
+                    // Every closed set of rotations gives a different stable matching, and
+                    // every stable matching comes from one of them
+                    let count = poset.rotations.len();
+                    let mut found = Vec::new();
+                    for subset in 0..(1 << count) {
+                        let closed: BTreeSet<usize> =
+                            (0..count).filter(|i| subset & (1 << i) != 0).collect();
+                        let is_closed = closed.iter().all(|b| {
+                            (0..count)
+                                .all(|a| !poset.successors[a].contains(b) || closed.contains(&a))
+                        });
+
+                        if is_closed {
+                            let matching = poset.matching(&closed);
+                            assert_eq!(all.contains(&matching), true);
+                            assert_eq!(found.contains(&matching), false);
+                            found.push(matching);
+                        }
+                    }
+                    assert_eq!(found.len(), all.len());
+
+                    assert_eq!(
+                        poset.to_dot().matches("->").count(),
+                        poset.successors.iter().map(|s| s.len()).sum::<usize>()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn all_stable_matchings_test() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..50 {
+            for n in 1..7 {
+                let (proposers, responders) = crate::input::random_incomplete_input(n, &mut rng);
+
+                let all = brute_force_matchings(&proposers, &responders);
+                let mut found = Vec::new();
+                for matching in super::all_stable_matchings(&proposers, &responders).unwrap() {
+                    assert_eq!(all.contains(&matching), true);
+                    assert_eq!(found.contains(&matching), false);
+                    found.push(matching);
+                }
+                assert_eq!(found.len(), all.len());
+            }
+        }
+    }
+
+    #[test]
+    fn count_stable_matchings_test() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..20 {
+            for n in 1..20 {
+                let (proposers, responders) = crate::input::random_input(n, &mut rng);
+
+                let count = super::all_stable_matchings(&proposers, &responders)
+                    .unwrap()
+                    .count() as u64;
+                assert_eq!(
+                    super::count_stable_matchings(&proposers, &responders, count, 0).unwrap(),
+                    super::StableMatchingCount::Exact(count)
+                );
+
+                // Going over the limit gives an estimate instead, which only depends on the seed
+                let estimate =
+                    super::count_stable_matchings(&proposers, &responders, count - 1, 0).unwrap();
+                match estimate {
+                    super::StableMatchingCount::Estimate(x) => assert_eq!(x >= 1.0, true),
+                    super::StableMatchingCount::Exact(_) => panic!("expected an estimate"),
+                }
+                assert_eq!(
+                    super::count_stable_matchings(&proposers, &responders, count - 1, 0).unwrap(),
+                    estimate
+                );
+            }
+        }
+
+        // Pairs of Proposers that can swap Responders without affecting anyone else give
+        // rotations that don't precede each other. Every path through the tree then has a
+        // choice at every rotation, so the estimate is exact whatever the seed
+        for k in 1..8 {
+            let mut proposers = Vec::new();
+            let mut responders = Vec::new();
+            for i in 0..k {
+                let (a, b) = (2 * i, 2 * i + 1);
+                proposers.push(ProposerInput::new(a, vec![b, a]));
+                proposers.push(ProposerInput::new(b, vec![a, b]));
+                responders.push(ResponderInput::new(a, vec![a, b]));
+                responders.push(ResponderInput::new(b, vec![b, a]));
+            }
+
+            let count = 1 << k;
+            assert_eq!(
+                super::count_stable_matchings(&proposers, &responders, count, 0).unwrap(),
+                super::StableMatchingCount::Exact(count)
+            );
+            for seed in 0..10 {
+                assert_eq!(
+                    super::count_stable_matchings(&proposers, &responders, count - 1, seed)
+                        .unwrap(),
+                    super::StableMatchingCount::Estimate(count as f64)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sample_stable_matching_test() {
+        let mut rng = rand::thread_rng();
+        for n in 1..20 {
+            let (proposers, responders) = crate::input::random_input(n, &mut rng);
+            let all: Vec<_> = super::all_stable_matchings(&proposers, &responders)
+                .unwrap()
+                .collect();
+
+            let first = super::sample_stable_matching(&proposers, &responders, n as u64).unwrap();
+            let second = super::sample_stable_matching(&proposers, &responders, n as u64).unwrap();
+            assert_eq!(first, second);
+
+            // Every stable matching should show up about as often as every other one with
+            // both the exact sampler and the Markov chain
+            let poset = super::RotationPoset::new(&proposers, &responders).unwrap();
+            let samples = 200 * all.len();
+            let mut exact = vec![0; all.len()];
+            let mut markov = vec![0; all.len()];
+            let mut seeded = rand::rngs::StdRng::seed_from_u64(n as u64);
+            for _ in 0..samples {
+                let closed = poset.exact_sample(all.len() as u64, &mut seeded);
+                let index = all.iter().position(|m| *m == poset.matching(&closed));
+                exact[index.unwrap()] += 1;
+
+                let closed = poset.markov_chain_sample(poset.mixing_steps(), &mut seeded);
+                let index = all.iter().position(|m| *m == poset.matching(&closed));
+                markov[index.unwrap()] += 1;
+            }
+
+            for count in exact.iter().chain(markov.iter()) {
+                assert_eq!(*count > 100 && *count < 300, true);
+            }
+        }
+    }
+
+    #[test]
+    fn meet_join_test() {
+        let mut rng = rand::thread_rng();
+        for n in 1..20 {
+            let (proposers, responders) = crate::input::random_input(n, &mut rng);
+            let all: Vec<_> = super::all_stable_matchings(&proposers, &responders)
+                .unwrap()
+                .collect();
+            let optimal = &all[0];
+            let pessimal = all.last().unwrap();
+
+            for a in all.iter() {
+                for b in all.iter() {
+                    let meet = super::meet(&proposers, &responders, a, b).unwrap();
+                    let join = super::join(&proposers, &responders, a, b).unwrap();
+                    assert_eq!(all.contains(&meet), true);
+                    assert_eq!(all.contains(&join), true);
+                    assert_eq!(
+                        super::meet(&proposers, &responders, &meet, a).unwrap(),
+                        meet
+                    );
+                    assert_eq!(
+                        super::join(&proposers, &responders, &join, a).unwrap(),
+                        join
+                    );
+                }
+
+                assert_eq!(
+                    super::meet(&proposers, &responders, a, optimal).unwrap(),
+                    *optimal
+                );
+                assert_eq!(
+                    super::join(&proposers, &responders, a, pessimal).unwrap(),
+                    *pessimal
+                );
+            }
+
+            // Matchings with agents that aren't in the instance are rejected
+            let mut unknown = optimal.clone();
+            unknown.insert(n, 0);
+            assert_eq!(
+                super::meet(&proposers, &responders, &unknown, optimal).is_err(),
+                true
+            );
+            assert_eq!(
+                super::join(&proposers, &responders, optimal, &unknown).is_err(),
+                true
+            );
+
+            let mut unknown = optimal.clone();
+            unknown.insert(0, n);
+            assert_eq!(
+                super::meet(&proposers, &responders, optimal, &unknown).is_err(),
+                true
+            );
+
+            // Matchings that are not stable are rejected
+            if n > 1 {
+                let mut unstable = optimal.clone();
+                unstable.remove(&0);
+                assert_eq!(
+                    super::meet(&proposers, &responders, &unstable, optimal).is_err(),
+                    true
+                );
+            }
+        }
+    }
+}
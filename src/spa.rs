@@ -0,0 +1,340 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{bail, Result};
+
+use crate::input::{LecturerId, LecturerInput, ProjectId, ProjectInput, StudentId, StudentInput};
+
+struct Allocation<'a> {
+    projects: &'a [ProjectInput],
+    lecturers: &'a [LecturerInput],
+    // Projects every Student still finds acceptable, ordered by ascending preference
+    lists: Vec<Vec<ProjectId>>,
+    // Mapping from LecturerId -> StudentId -> preference, or None if the Student is not
+    // acceptable
+    ranks: Vec<Vec<Option<usize>>>,
+    assignment: Vec<Option<ProjectId>>,
+    project_students: Vec<BTreeSet<StudentId>>,
+    lecturer_students: Vec<BTreeSet<StudentId>>,
+}
+
+impl<'a> Allocation<'a> {
+    fn new(
+        students: &[StudentInput],
+        projects: &'a [ProjectInput],
+        lecturers: &'a [LecturerInput],
+    ) -> Result<Self> {
+        for (index, p) in projects.iter().enumerate() {
+            if p.id as usize != index || p.lecturer as usize >= lecturers.len() {
+                bail!("received invalid project: {:?}", p);
+            }
+        }
+
+        let mut ranks = Vec::new();
+        for (index, l) in lecturers.iter().enumerate() {
+            let mut rank: Vec<Option<usize>> = vec![None; students.len()];
+            for (preference, s) in l.preferences.iter().enumerate() {
+                match rank.get_mut(*s as usize) {
+                    Some(slot) if slot.is_none() && l.id as usize == index => {
+                        *slot = Some(preference)
+                    }
+                    _ => bail!(
+                        "received invalid preferences in lecturer: {} preferences: {:?}",
+                        l.id,
+                        l.preferences
+                    ),
+                }
+            }
+            ranks.push(rank);
+        }
+
+        let mut lists = Vec::new();
+        for (index, s) in students.iter().enumerate() {
+            if s.id as usize != index || s.preferences.iter().any(|p| *p as usize >= projects.len())
+            {
+                bail!(
+                    "received invalid preferences in student: {} preferences: {:?}",
+                    s.id,
+                    s.preferences
+                );
+            }
+
+            // Projects whose Lecturer does not rank the Student can never be assigned
+            let list = s
+                .preferences
+                .iter()
+                .filter(|p| ranks[projects[**p as usize].lecturer as usize][index].is_some())
+                .cloned()
+                .collect();
+            lists.push(list);
+        }
+
+        Ok(Allocation {
+            projects,
+            lecturers,
+            lists,
+            ranks,
+            assignment: vec![None; students.len()],
+            project_students: vec![BTreeSet::new(); projects.len()],
+            lecturer_students: vec![BTreeSet::new(); lecturers.len()],
+        })
+    }
+
+    fn lecturer(&self, p: ProjectId) -> LecturerId {
+        self.projects[p as usize].lecturer
+    }
+
+    fn rank(&self, l: LecturerId, s: StudentId) -> usize {
+        self.ranks[l as usize][s as usize].expect("student known to be acceptable")
+    }
+
+    fn project_full(&self, p: ProjectId) -> bool {
+        self.project_students[p as usize].len() >= self.projects[p as usize].capacity
+    }
+
+    fn lecturer_full(&self, l: LecturerId) -> bool {
+        self.lecturer_students[l as usize].len() >= self.lecturers[l as usize].capacity
+    }
+
+    // The Student the Lecturer likes the least out of the given ones
+    fn worst(&self, l: LecturerId, students: &BTreeSet<StudentId>) -> Option<StudentId> {
+        students.iter().cloned().min_by_key(|s| self.rank(l, *s))
+    }
+
+    fn assign(&mut self, s: StudentId, p: ProjectId) {
+        let l = self.lecturer(p);
+        self.assignment[s as usize] = Some(p);
+        self.project_students[p as usize].insert(s);
+        self.lecturer_students[l as usize].insert(s);
+    }
+
+    fn unassign(&mut self, s: StudentId) {
+        if let Some(p) = self.assignment[s as usize].take() {
+            let l = self.lecturer(p);
+            self.project_students[p as usize].remove(&s);
+            self.lecturer_students[l as usize].remove(&s);
+        }
+    }
+
+    fn delete(&mut self, s: StudentId, p: ProjectId) {
+        self.lists[s as usize].retain(|x| *x != p);
+    }
+
+    // Every Student the Lecturer likes less than s
+    fn successors(&self, l: LecturerId, s: StudentId) -> Vec<StudentId> {
+        let rank = self.rank(l, s);
+        self.lecturers[l as usize].preferences[..rank].to_vec()
+    }
+
+    fn matching(&self) -> HashMap<StudentId, ProjectId> {
+        self.assignment
+            .iter()
+            .enumerate()
+            .filter_map(|(s, p)| p.map(|p| (s as StudentId, p)))
+            .collect()
+    }
+}
+
+// Abraham, Irving and Manlove's SPA-student algorithm. Students apply to their favourite
+// Project, and whenever a Project or Lecturer fills up, the Students ranked below the
+// worst one they hold can never get in and are removed. Every Student ends up with the
+// best Project they have in any stable matching
+pub fn student_optimal_matching(
+    students: &[StudentInput],
+    projects: &[ProjectInput],
+    lecturers: &[LecturerInput],
+) -> Result<HashMap<StudentId, ProjectId>> {
+    let mut allocation = Allocation::new(students, projects, lecturers)?;
+    let mut free: Vec<StudentId> = students.iter().rev().map(|s| s.id).collect();
+
+    while let Some(s) = free.pop() {
+        let p = match allocation.lists[s as usize].last() {
+            Some(p) => *p,
+            None => continue,
+        };
+        let l = allocation.lecturer(p);
+
+        allocation.assign(s, p);
+
+        // Over-subscribed Projects and Lecturers drop the Student they like the least
+        if allocation.project_students[p as usize].len() > projects[p as usize].capacity {
+            let worst = allocation.worst(l, &allocation.project_students[p as usize]);
+            let worst = worst.expect("project known to be over-subscribed");
+            allocation.unassign(worst);
+            free.push(worst);
+        } else if allocation.lecturer_students[l as usize].len() > lecturers[l as usize].capacity {
+            let worst = allocation.worst(l, &allocation.lecturer_students[l as usize]);
+            let worst = worst.expect("lecturer known to be over-subscribed");
+            allocation.unassign(worst);
+            free.push(worst);
+        }
+
+        if allocation.project_full(p) {
+            if let Some(worst) = allocation.worst(l, &allocation.project_students[p as usize]) {
+                for t in allocation.successors(l, worst) {
+                    allocation.delete(t, p);
+                }
+            }
+        }
+
+        if allocation.lecturer_full(l) {
+            if let Some(worst) = allocation.worst(l, &allocation.lecturer_students[l as usize]) {
+                let offered: Vec<_> = projects.iter().filter(|x| x.lecturer == l).collect();
+                for t in allocation.successors(l, worst) {
+                    for u in offered.iter() {
+                        allocation.delete(t, u.id);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(allocation.matching())
+}
+
+// Abraham, Irving and Manlove's SPA-lecturer algorithm. Under-subscribed Lecturers offer
+// their favourite Student a place on the best Project that Student would still take,
+// and Students that accept drop every Project they like less
+pub fn lecturer_optimal_matching(
+    students: &[StudentInput],
+    projects: &[ProjectInput],
+    lecturers: &[LecturerInput],
+) -> Result<HashMap<StudentId, ProjectId>> {
+    let mut allocation = Allocation::new(students, projects, lecturers)?;
+
+    loop {
+        let mut offer = None;
+
+        for l in lecturers.iter().filter(|l| !allocation.lecturer_full(l.id)) {
+            for s in l.preferences.iter().rev() {
+                // The first Project on the Student's list that is offered by l, has room,
+                // and that the Student is not already assigned to
+                let project = allocation.lists[*s as usize].iter().rev().find(|p| {
+                    allocation.lecturer(**p) == l.id
+                        && !allocation.project_full(**p)
+                        && allocation.assignment[*s as usize] != Some(**p)
+                });
+
+                if let Some(p) = project {
+                    offer = Some((*s, *p));
+                    break;
+                }
+            }
+
+            if offer.is_some() {
+                break;
+            }
+        }
+
+        let (s, p) = match offer {
+            Some(offer) => offer,
+            None => break,
+        };
+
+        allocation.unassign(s);
+        allocation.assign(s, p);
+
+        // The Student would never move to a Project they like less
+        let list = &mut allocation.lists[s as usize];
+        let index = list
+            .iter()
+            .position(|x| *x == p)
+            .expect("project known to be listed");
+        list.drain(..index);
+    }
+
+    Ok(allocation.matching())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::input::{validate_spa_matching, LecturerInput, ProjectInput, StudentInput};
+
+    // Every stable matching, found by brute force
+    fn brute_force_matchings(
+        students: &[StudentInput],
+        projects: &[ProjectInput],
+        lecturers: &[LecturerInput],
+    ) -> Vec<HashMap<u32, u32>> {
+        fn extend(
+            index: usize,
+            students: &[StudentInput],
+            projects: &[ProjectInput],
+            lecturers: &[LecturerInput],
+            current: &mut HashMap<u32, u32>,
+            all: &mut Vec<HashMap<u32, u32>>,
+        ) {
+            if index == students.len() {
+                if validate_spa_matching(students, projects, lecturers, current) {
+                    all.push(current.clone());
+                }
+                return;
+            }
+
+            extend(index + 1, students, projects, lecturers, current, all);
+
+            for p in students[index].preferences.iter() {
+                current.insert(students[index].id, *p);
+                extend(index + 1, students, projects, lecturers, current, all);
+                current.remove(&students[index].id);
+            }
+        }
+
+        let mut all = Vec::new();
+        extend(
+            0,
+            students,
+            projects,
+            lecturers,
+            &mut HashMap::new(),
+            &mut all,
+        );
+        all
+    }
+
+    #[test]
+    fn spa_test() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..20 {
+            for num_students in 1..6 {
+                for num_projects in 1..5 {
+                    for num_lecturers in 1..3 {
+                        let (students, projects, lecturers) = crate::input::random_spa_input(
+                            num_students,
+                            num_projects,
+                            num_lecturers,
+                            2,
+                            &mut rng,
+                        );
+
+                        let all = brute_force_matchings(&students, &projects, &lecturers);
+                        let student_optimal =
+                            super::student_optimal_matching(&students, &projects, &lecturers)
+                                .unwrap();
+                        let lecturer_optimal =
+                            super::lecturer_optimal_matching(&students, &projects, &lecturers)
+                                .unwrap();
+
+                        assert_eq!(all.contains(&student_optimal), true);
+                        assert_eq!(all.contains(&lecturer_optimal), true);
+
+                        // Students do at least as well in the student-optimal matching as
+                        // in any other stable matching, and at most as well in the
+                        // lecturer-optimal one
+                        let position = |s: &StudentInput, m: &HashMap<u32, u32>| {
+                            m.get(&s.id)
+                                .and_then(|p| s.preferences.iter().position(|x| x == p))
+                        };
+                        for m in all.iter() {
+                            for s in students.iter() {
+                                assert_eq!(position(s, &student_optimal) >= position(s, m), true);
+                                assert_eq!(position(s, &lecturer_optimal) <= position(s, m), true);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
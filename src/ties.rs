@@ -0,0 +1,502 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{bail, Result};
+
+use crate::input::{
+    tied_blocking_pair, validate_strong_matching, validate_super_matching, ProposerId, ResponderId,
+    Stability, TiedMatchingInput, TiedProposerInput, TiedResponderInput,
+};
+
+#[derive(Debug)]
+pub enum TiedMatchingOutcome {
+    Matching(HashMap<ProposerId, ResponderId>),
+    NoMatching(NonexistenceReport),
+}
+
+// Explains why no matching with the requested notion of stability exists. Irving's
+// algorithms find such a matching whenever one exists, so when the closest
+// matching they can build is blocked, no matching is stable in the requested sense
+#[derive(Debug, Default)]
+pub struct NonexistenceReport {
+    // Proposers that were rejected by every Responder they found acceptable
+    pub exhausted_proposers: BTreeSet<ProposerId>,
+    // Proposers that were left engaged to more than one Responder
+    pub multiply_engaged_proposers: BTreeSet<ProposerId>,
+    // Responders that were engaged at some point but ended up unmatched
+    pub abandoned_responders: BTreeSet<ResponderId>,
+    // A pair blocking the matching closest to a solution that the algorithm found
+    pub blocking_pair: Option<(ProposerId, ResponderId)>,
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    // Reduced preference lists, as indifference classes ordered by ascending preference
+    // Relies on ProposerIds and ResponderIds being in the domain [0, n) where n is the
+    // number of Proposers and Responders respectively
+    proposers: Vec<Vec<Vec<ResponderId>>>,
+    responders: Vec<Vec<Vec<ProposerId>>>,
+    proposer_engagements: Vec<BTreeSet<ResponderId>>,
+    responder_engagements: Vec<BTreeSet<ProposerId>>,
+    ever_engaged: BTreeSet<ResponderId>,
+}
+
+impl Table {
+    fn new(proposers: &[TiedProposerInput], responders: &[TiedResponderInput]) -> Result<Self> {
+        let mut table = Table {
+            proposers: vec![Vec::new(); proposers.len()],
+            responders: vec![Vec::new(); responders.len()],
+            proposer_engagements: vec![BTreeSet::new(); proposers.len()],
+            responder_engagements: vec![BTreeSet::new(); responders.len()],
+            ever_engaged: BTreeSet::new(),
+        };
+
+        for p in proposers.iter() {
+            if p.id as usize >= proposers.len()
+                || p.preferences
+                    .iter()
+                    .flatten()
+                    .any(|r| *r as usize >= responders.len())
+            {
+                bail!(
+                    "received invalid proposer input id: {} preferences: {:?}",
+                    p.id,
+                    p.preferences
+                );
+            }
+        }
+
+        for r in responders.iter() {
+            if r.id as usize >= responders.len()
+                || r.preferences
+                    .iter()
+                    .flatten()
+                    .any(|p| *p as usize >= proposers.len())
+            {
+                bail!(
+                    "received invalid responder input id: {} preferences: {:?}",
+                    r.id,
+                    r.preferences
+                );
+            }
+        }
+
+        // Only keep the pairs that are mutually acceptable
+        for p in proposers.iter() {
+            table.proposers[p.id as usize] = p
+                .preferences
+                .iter()
+                .map(|tier| {
+                    tier.iter()
+                        .filter(|r| {
+                            responders
+                                .iter()
+                                .any(|x| x.id == **r && x.tier(p.id).is_some())
+                        })
+                        .cloned()
+                        .collect::<Vec<_>>()
+                })
+                .filter(|tier| !tier.is_empty())
+                .collect();
+        }
+
+        for r in responders.iter() {
+            table.responders[r.id as usize] = r
+                .preferences
+                .iter()
+                .map(|tier| {
+                    tier.iter()
+                        .filter(|p| {
+                            proposers
+                                .iter()
+                                .any(|x| x.id == **p && x.tier(r.id).is_some())
+                        })
+                        .cloned()
+                        .collect::<Vec<_>>()
+                })
+                .filter(|tier| !tier.is_empty())
+                .collect();
+        }
+
+        Ok(table)
+    }
+
+    // The indifference class of Responders this Proposer likes most
+    fn head(&self, proposer: ProposerId) -> Vec<ResponderId> {
+        self.proposers[proposer as usize]
+            .last()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // The indifference class of Proposers this Responder likes least
+    fn tail(&self, responder: ResponderId) -> Vec<ProposerId> {
+        self.responders[responder as usize]
+            .first()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Every Proposer this Responder likes strictly less than proposer
+    fn strict_successors(&self, responder: ResponderId, proposer: ProposerId) -> Vec<ProposerId> {
+        let tiers = &self.responders[responder as usize];
+
+        match tiers.iter().position(|tier| tier.contains(&proposer)) {
+            Some(index) => tiers[..index].concat(),
+            None => Vec::new(),
+        }
+    }
+
+    fn engage(&mut self, proposer: ProposerId, responder: ResponderId) {
+        self.proposer_engagements[proposer as usize].insert(responder);
+        self.responder_engagements[responder as usize].insert(proposer);
+        self.ever_engaged.insert(responder);
+    }
+
+    fn break_engagements(&mut self, responder: ResponderId) {
+        let engagements = std::mem::replace(
+            &mut self.responder_engagements[responder as usize],
+            BTreeSet::new(),
+        );
+
+        for p in engagements.into_iter() {
+            self.proposer_engagements[p as usize].remove(&responder);
+        }
+    }
+
+    // Remove the pair from both preference lists, along with any engagement between them
+    fn delete(&mut self, proposer: ProposerId, responder: ResponderId) {
+        remove_from_tiers(&mut self.proposers[proposer as usize], responder);
+        remove_from_tiers(&mut self.responders[responder as usize], proposer);
+        self.proposer_engagements[proposer as usize].remove(&responder);
+        self.responder_engagements[responder as usize].remove(&proposer);
+    }
+
+    fn free_proposer(&self) -> Option<ProposerId> {
+        (0..self.proposers.len())
+            .find(|p| self.proposer_engagements[*p].is_empty() && !self.proposers[*p].is_empty())
+            .map(|p| p as ProposerId)
+    }
+
+    fn propose(&mut self) {
+        // Every free Proposer becomes engaged to every Responder at the head of its list,
+        // and those Responders will never accept anyone they like strictly less
+        while let Some(p) = self.free_proposer() {
+            for r in self.head(p).into_iter() {
+                self.engage(p, r);
+
+                for successor in self.strict_successors(r, p).into_iter() {
+                    self.delete(successor, r);
+                }
+            }
+        }
+    }
+
+    fn report(
+        &self,
+        proposers: &[TiedProposerInput],
+        responders: &[TiedResponderInput],
+        matching: &HashMap<ProposerId, ResponderId>,
+        stability: Stability,
+    ) -> NonexistenceReport {
+        let matched: BTreeSet<_> = matching.values().cloned().collect();
+
+        NonexistenceReport {
+            exhausted_proposers: proposers
+                .iter()
+                .filter(|p| !p.preferences.is_empty() && self.proposers[p.id as usize].is_empty())
+                .map(|p| p.id)
+                .collect(),
+            multiply_engaged_proposers: (0..self.proposers.len())
+                .filter(|p| self.proposer_engagements[*p].len() > 1)
+                .map(|p| p as ProposerId)
+                .collect(),
+            abandoned_responders: self.ever_engaged.difference(&matched).cloned().collect(),
+            blocking_pair: tied_blocking_pair(proposers, responders, matching, stability),
+        }
+    }
+}
+
+fn remove_from_tiers(tiers: &mut Vec<Vec<u32>>, x: u32) {
+    for tier in tiers.iter_mut() {
+        tier.retain(|y| *y != x);
+    }
+    tiers.retain(|tier| !tier.is_empty());
+}
+
+// Find a maximum matching in the bipartite graph given by the adjacency lists of
+// every Proposer, and return the Responder each Proposer gets
+fn maximum_matching(
+    adjacency: &[BTreeSet<ResponderId>],
+    num_responders: usize,
+) -> Vec<Option<ResponderId>> {
+    fn augment(
+        proposer: usize,
+        adjacency: &[BTreeSet<ResponderId>],
+        matched: &mut Vec<Option<ProposerId>>,
+        visited: &mut Vec<bool>,
+    ) -> bool {
+        for r in adjacency[proposer].iter() {
+            let r = *r as usize;
+            if visited[r] {
+                continue;
+            }
+            visited[r] = true;
+
+            let augmenting = match matched[r] {
+                Some(other) => augment(other as usize, adjacency, matched, visited),
+                None => true,
+            };
+
+            if augmenting {
+                matched[r] = Some(proposer as ProposerId);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    let mut matched: Vec<Option<ProposerId>> = vec![None; num_responders];
+    for p in 0..adjacency.len() {
+        let mut visited = vec![false; num_responders];
+        augment(p, adjacency, &mut matched, &mut visited);
+    }
+
+    let mut result = vec![None; adjacency.len()];
+    for (r, p) in matched.iter().enumerate() {
+        if let Some(p) = p {
+            result[*p as usize] = Some(r as ResponderId);
+        }
+    }
+
+    result
+}
+
+pub fn super_stable_matching(
+    proposers: &[TiedProposerInput],
+    responders: &[TiedResponderInput],
+) -> Result<TiedMatchingOutcome> {
+    let mut table = Table::new(proposers, responders)?;
+
+    loop {
+        table.propose();
+
+        // A Responder engaged to several Proposers is indifferent between them, so
+        // none of them can be its partner in a super-stable matching
+        let multiply_engaged: Vec<_> = (0..responders.len())
+            .filter(|r| table.responder_engagements[*r].len() > 1)
+            .map(|r| r as ResponderId)
+            .collect();
+
+        if multiply_engaged.is_empty() {
+            break;
+        }
+
+        for r in multiply_engaged.into_iter() {
+            table.break_engagements(r);
+
+            for p in table.tail(r).into_iter() {
+                table.delete(p, r);
+            }
+        }
+    }
+
+    let matching: HashMap<_, _> = table
+        .proposer_engagements
+        .iter()
+        .enumerate()
+        .filter(|(_, engagements)| engagements.len() == 1)
+        .map(|(p, engagements)| {
+            let r = *engagements
+                .iter()
+                .next()
+                .expect("engagement known to exist");
+            (p as ProposerId, r)
+        })
+        .collect();
+
+    if validate_super_matching(proposers, responders, &matching) {
+        Ok(TiedMatchingOutcome::Matching(matching))
+    } else {
+        Ok(TiedMatchingOutcome::NoMatching(table.report(
+            proposers,
+            responders,
+            &matching,
+            Stability::Super,
+        )))
+    }
+}
+
+pub fn strongly_stable_matching(
+    proposers: &[TiedProposerInput],
+    responders: &[TiedResponderInput],
+) -> Result<TiedMatchingOutcome> {
+    let mut table = Table::new(proposers, responders)?;
+
+    loop {
+        table.propose();
+
+        // Find the critical set of Proposers: the ones reachable by alternating paths
+        // from a Proposer that a maximum matching of the engagement graph leaves out.
+        // Their neighbourhood has too few Responders to go around, so the Proposers at
+        // the tail of those Responders' lists can't be matched with them
+        let matched = maximum_matching(&table.proposer_engagements, responders.len());
+        let mut matched_proposer: Vec<Option<ProposerId>> = vec![None; responders.len()];
+        for (p, r) in matched.iter().enumerate() {
+            if let Some(r) = r {
+                matched_proposer[*r as usize] = Some(p as ProposerId);
+            }
+        }
+
+        let mut to_visit: Vec<ProposerId> = (0..proposers.len())
+            .filter(|p| matched[*p].is_none() && !table.proposer_engagements[*p].is_empty())
+            .map(|p| p as ProposerId)
+            .collect();
+        let mut visited: BTreeSet<ProposerId> = to_visit.iter().cloned().collect();
+        let mut neighbourhood: BTreeSet<ResponderId> = BTreeSet::new();
+
+        while let Some(p) = to_visit.pop() {
+            for r in table.proposer_engagements[p as usize].iter() {
+                if !neighbourhood.insert(*r) {
+                    continue;
+                }
+
+                if let Some(q) = matched_proposer[*r as usize] {
+                    if visited.insert(q) {
+                        to_visit.push(q);
+                    }
+                }
+            }
+        }
+
+        if neighbourhood.is_empty() {
+            break;
+        }
+
+        for r in neighbourhood.into_iter() {
+            for p in table.tail(r).into_iter() {
+                table.delete(p, r);
+            }
+        }
+    }
+
+    let matching: HashMap<_, _> = maximum_matching(&table.proposer_engagements, responders.len())
+        .into_iter()
+        .enumerate()
+        .filter_map(|(p, r)| r.map(|r| (p as ProposerId, r)))
+        .collect();
+
+    if validate_strong_matching(proposers, responders, &matching) {
+        Ok(TiedMatchingOutcome::Matching(matching))
+    } else {
+        Ok(TiedMatchingOutcome::NoMatching(table.report(
+            proposers,
+            responders,
+            &matching,
+            Stability::Strong,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+    use rand::Rng;
+
+    use super::TiedMatchingOutcome;
+    use crate::input::{TiedProposerInput, TiedResponderInput};
+
+    // Every matching of mutually acceptable pairs, found by brute force
+    fn all_matchings(
+        proposers: &[TiedProposerInput],
+        responders: &[TiedResponderInput],
+    ) -> Vec<HashMap<u32, u32>> {
+        fn extend(
+            index: usize,
+            proposers: &[TiedProposerInput],
+            responders: &[TiedResponderInput],
+            current: &mut HashMap<u32, u32>,
+            all: &mut Vec<HashMap<u32, u32>>,
+        ) {
+            if index == proposers.len() {
+                all.push(current.clone());
+                return;
+            }
+
+            extend(index + 1, proposers, responders, current, all);
+
+            let p = &proposers[index];
+            for r in p.preferences.iter().flatten() {
+                let acceptable = responders[*r as usize]
+                    .preferences
+                    .iter()
+                    .any(|tier| tier.contains(&p.id));
+
+                if acceptable && !current.values().any(|x| x == r) {
+                    current.insert(p.id, *r);
+                    extend(index + 1, proposers, responders, current, all);
+                    current.remove(&p.id);
+                }
+            }
+        }
+
+        let mut all = Vec::new();
+        extend(0, proposers, responders, &mut HashMap::new(), &mut all);
+        all
+    }
+
+    fn check(
+        solver: fn(&[TiedProposerInput], &[TiedResponderInput]) -> Result<TiedMatchingOutcome>,
+        validate: fn(&[TiedProposerInput], &[TiedResponderInput], &HashMap<u32, u32>) -> bool,
+    ) {
+        let mut rng = rand::thread_rng();
+        for _ in 0..200 {
+            for n in 1..6 {
+                let (mut proposers, mut responders) = crate::input::random_tied_input(n, &mut rng);
+
+                // Make some of the lists incomplete
+                for p in proposers.iter_mut() {
+                    for tier in p.preferences.iter_mut() {
+                        tier.retain(|_| rng.gen_bool(0.8));
+                    }
+                    p.preferences.retain(|tier| !tier.is_empty());
+                }
+                for r in responders.iter_mut() {
+                    for tier in r.preferences.iter_mut() {
+                        tier.retain(|_| rng.gen_bool(0.8));
+                    }
+                    r.preferences.retain(|tier| !tier.is_empty());
+                }
+
+                let exists = all_matchings(&proposers, &responders)
+                    .iter()
+                    .any(|m| validate(&proposers, &responders, m));
+
+                match solver(&proposers, &responders).unwrap() {
+                    TiedMatchingOutcome::Matching(matching) => {
+                        assert_eq!(validate(&proposers, &responders, &matching), true);
+                    }
+                    TiedMatchingOutcome::NoMatching(_) => assert_eq!(exists, false),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn super_stable_test() {
+        check(
+            super::super_stable_matching,
+            crate::input::validate_super_matching,
+        );
+    }
+
+    #[test]
+    fn strongly_stable_test() {
+        check(
+            super::strongly_stable_matching,
+            crate::input::validate_strong_matching,
+        );
+    }
+}
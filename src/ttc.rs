@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::input::{validate_input, ProposerId, ProposerInput, ResponderId, ResponderInput};
+
+#[derive(Debug)]
+pub struct TradingCycles {
+    pub matching: HashMap<ProposerId, ResponderId>,
+    // The cycles cleared in every round, where each cycle lists the Proposers in it along
+    // with the Responder they were given
+    pub rounds: Vec<Vec<Vec<(ProposerId, ResponderId)>>>,
+}
+
+// Gale's Top Trading Cycles, with Responder preferences used as priorities. In every round
+// each Proposer points to its favourite Responder that still has room and ranks it, and
+// each such Responder points to the Proposer it ranks highest. Everyone on a cycle gets
+// the Responder they point to. The result is Pareto efficient and strategy-proof for the
+// Proposers, but unlike deferred acceptance it can leave justified envy. House allocation
+// with existing tenants is the case where every house ranks its tenant first
+pub fn top_trading_cycles(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+) -> Result<TradingCycles> {
+    validate_input(proposers, responders)?;
+
+    // Mapping from ResponderId -> ProposerId -> priority, or None if the Proposer is not
+    // acceptable
+    let mut priorities = vec![vec![None; proposers.len()]; responders.len()];
+    for r in responders.iter() {
+        for (index, p) in r.preferences.iter().enumerate() {
+            priorities[r.id as usize][*p as usize] = Some(index);
+        }
+    }
+
+    let mut capacity: Vec<usize> = responders.iter().map(|r| r.capacity).collect();
+    let mut remaining: Vec<bool> = vec![true; proposers.len()];
+    let mut matching = HashMap::new();
+    let mut rounds = Vec::new();
+
+    loop {
+        // Proposers that can no longer get any Responder leave unassigned, and can never
+        // come back since capacities only go down
+        let mut points_to: Vec<Option<ResponderId>> = vec![None; proposers.len()];
+        for p in proposers.iter() {
+            if !remaining[p.id as usize] {
+                continue;
+            }
+
+            points_to[p.id as usize] = p
+                .preferences
+                .iter()
+                .rev()
+                .find(|r| {
+                    capacity[**r as usize] > 0 && priorities[**r as usize][p.id as usize].is_some()
+                })
+                .cloned();
+
+            if points_to[p.id as usize].is_none() {
+                remaining[p.id as usize] = false;
+            }
+        }
+
+        if !remaining.iter().any(|x| *x) {
+            break;
+        }
+
+        // Every Responder someone points to ranks that Proposer, so it has someone to
+        // point back to
+        let pointed_back = |r: ResponderId| -> ProposerId {
+            (0..proposers.len())
+                .filter(|p| remaining[*p])
+                .filter_map(|p| priorities[r as usize][p].map(|x| (x, p as ProposerId)))
+                .max()
+                .map(|(_, p)| p)
+                .expect("responder known to rank a remaining proposer")
+        };
+        let next: Vec<Option<ProposerId>> = points_to.iter().map(|r| r.map(pointed_back)).collect();
+
+        // Every remaining Proposer leads to a cycle, since following the pointers always
+        // gets us to another remaining Proposer. 0 means unvisited, 1 means visited this
+        // walk and 2 means done
+        let mut state = vec![0; proposers.len()];
+        let mut cycles = Vec::new();
+        for start in 0..proposers.len() {
+            if !remaining[start] || state[start] != 0 {
+                continue;
+            }
+
+            let mut walk = Vec::new();
+            let mut p = start;
+            while state[p] == 0 {
+                state[p] = 1;
+                walk.push(p);
+                p = next[p].expect("proposer known to be remaining") as usize;
+            }
+
+            if state[p] == 1 {
+                let position = walk
+                    .iter()
+                    .position(|x| *x == p)
+                    .expect("proposer known to be on this walk");
+                let cycle: Vec<(ProposerId, ResponderId)> = walk[position..]
+                    .iter()
+                    .map(|p| {
+                        let r = points_to[*p].expect("proposer known to be remaining");
+                        (*p as ProposerId, r)
+                    })
+                    .collect();
+                cycles.push(cycle);
+            }
+
+            for p in walk {
+                state[p] = 2;
+            }
+        }
+
+        for (p, r) in cycles.iter().flatten() {
+            matching.insert(*p, *r);
+            remaining[*p as usize] = false;
+            capacity[*r as usize] -= 1;
+        }
+        rounds.push(cycles);
+    }
+
+    Ok(TradingCycles { matching, rounds })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::input::{is_pareto_efficient, justified_envy_count, MatchingInput, Side};
+
+    #[test]
+    fn top_trading_cycles_test() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            for num_proposers in 1..20 {
+                for num_responders in 1..6 {
+                    let (proposers, responders) = crate::input::random_many_to_one_input(
+                        num_proposers,
+                        num_responders,
+                        3,
+                        &mut rng,
+                    );
+
+                    let result = super::top_trading_cycles(&proposers, &responders).unwrap();
+                    let matching = &result.matching;
+
+                    for (p, r) in matching.iter() {
+                        assert_eq!(proposers[*p as usize].accepts(*r), true);
+                        assert_eq!(responders[*r as usize].accepts(*p), true);
+                    }
+                    for r in responders.iter() {
+                        let assigned = matching.values().filter(|x| **x == r.id).count();
+                        assert_eq!(assigned <= r.capacity, true);
+                    }
+
+                    // Every assignment was made on exactly one cycle
+                    let cleared: HashMap<_, _> =
+                        result.rounds.iter().flatten().flatten().cloned().collect();
+                    assert_eq!(&cleared, matching);
+                    assert_eq!(
+                        result.rounds.iter().flatten().flatten().count(),
+                        matching.len()
+                    );
+                    for round in result.rounds.iter() {
+                        assert_eq!(round.iter().all(|cycle| !cycle.is_empty()), true);
+                    }
+
+                    assert_eq!(is_pareto_efficient(&proposers, &responders, matching), true);
+
+                    // Deferred acceptance never leaves justified envy, but need not be
+                    // Pareto efficient
+                    let stable = crate::hospitals_residents::stable_matching(
+                        &proposers,
+                        &responders,
+                        Side::Proposer,
+                    )
+                    .unwrap();
+                    let stable: HashMap<_, _> = stable
+                        .into_iter()
+                        .flat_map(|(r, ps)| ps.into_iter().map(move |p| (p, r)))
+                        .collect();
+                    assert_eq!(justified_envy_count(&proposers, &responders, &stable), 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn house_allocation_test() {
+        use crate::input::{ProposerInput, ResponderInput};
+
+        // Every house ranks its tenant first, so the tenants trade houses along the
+        // cycle 0 -> 1 -> 2 -> 0 in the first round, and 3 keeps its own house
+        let proposers = vec![
+            ProposerInput::new(0, vec![0, 1]),
+            ProposerInput::new(1, vec![1, 2]),
+            ProposerInput::new(2, vec![2, 0]),
+            ProposerInput::new(3, vec![0, 3]),
+        ];
+        let responders = vec![
+            ResponderInput::new(0, vec![3, 1, 2, 0]),
+            ResponderInput::new(1, vec![3, 2, 0, 1]),
+            ResponderInput::new(2, vec![3, 1, 0, 2]),
+            ResponderInput::new(3, vec![2, 1, 0, 3]),
+        ];
+
+        let result = super::top_trading_cycles(&proposers, &responders).unwrap();
+
+        let expected: HashMap<_, _> = vec![(0, 1), (1, 2), (2, 0), (3, 3)].into_iter().collect();
+        assert_eq!(result.matching, expected);
+        assert_eq!(result.rounds.len(), 1);
+        assert_eq!(result.rounds[0].len(), 2);
+        assert_eq!(
+            is_pareto_efficient(&proposers, &responders, &expected),
+            true
+        );
+
+        // Everyone got their favourite house, so nobody envies anyone
+        assert_eq!(
+            justified_envy_count(&proposers, &responders, &result.matching),
+            0
+        );
+    }
+
+    #[test]
+    fn school_choice_test() {
+        use crate::input::{ProposerInput, ResponderInput};
+
+        // Students 0 and 1 trade their top priorities at schools 1 and 0, which leaves
+        // student 2 envying student 1 at school 0 where it has the higher priority
+        let proposers = vec![
+            ProposerInput::new(0, vec![2, 0, 1]),
+            ProposerInput::new(1, vec![2, 1, 0]),
+            ProposerInput::new(2, vec![2, 1, 0]),
+        ];
+        let responders = vec![
+            ResponderInput::new(0, vec![1, 2, 0]),
+            ResponderInput::new(1, vec![2, 0, 1]),
+            ResponderInput::new(2, vec![0, 1, 2]),
+        ];
+
+        let result = super::top_trading_cycles(&proposers, &responders).unwrap();
+
+        let expected: HashMap<_, _> = vec![(0, 1), (1, 0), (2, 2)].into_iter().collect();
+        assert_eq!(result.matching, expected);
+        assert_eq!(
+            result.rounds,
+            vec![vec![vec![(0, 1), (1, 0)]], vec![vec![(2, 2)]]]
+        );
+        assert_eq!(
+            is_pareto_efficient(&proposers, &responders, &expected),
+            true
+        );
+        assert_eq!(justified_envy_count(&proposers, &responders, &expected), 1);
+
+        // Deferred acceptance removes the envy, but 0 and 1 would both rather swap
+        let stable: HashMap<_, _> = vec![(0, 0), (1, 1), (2, 2)].into_iter().collect();
+        let found =
+            crate::hospitals_residents::stable_matching(&proposers, &responders, Side::Proposer)
+                .unwrap();
+        for (r, ps) in found.iter() {
+            for p in ps.iter() {
+                assert_eq!(stable[p], *r);
+            }
+        }
+        assert_eq!(is_pareto_efficient(&proposers, &responders, &stable), false);
+        assert_eq!(justified_envy_count(&proposers, &responders, &stable), 0);
+    }
+}
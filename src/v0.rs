@@ -4,19 +4,29 @@ use std::iter::FromIterator;
 
 use anyhow::{bail, Result};
 
-use crate::input::{ProposerId, ProposerInput, ResponderId, ResponderInput};
+use crate::input::{
+    swap_sides, validate_one_to_one_input, Constraints, MatchingResult, ProposerId, ProposerInput,
+    ResponderId, ResponderInput, Side,
+};
 
 #[derive(Debug, Default, Eq, Hash, PartialEq)]
 struct Proposer<'a> {
     id: ProposerId,
     // List of Responders ordered by ascending preference
     preferences: &'a [ResponderId],
-    preferences_index: usize,
+    // Number of Responders that have not rejected this Proposer yet. The next
+    // Responder to propose to is preferences[remaining - 1]
+    remaining: usize,
 }
 
 impl<'a> Proposer<'a> {
-    fn new(id: ProposerId, preferences: &'a [ResponderId]) -> Result<Self> {
-        if id as usize >= preferences.len() {
+    fn new(
+        id: ProposerId,
+        preferences: &'a [ResponderId],
+        num_proposers: usize,
+        num_responders: usize,
+    ) -> Result<Self> {
+        if id as usize >= num_proposers {
             bail!(
                 "received invalid proposer input id: {} preferences: {:?}",
                 id,
@@ -25,7 +35,7 @@ impl<'a> Proposer<'a> {
         }
 
         for p in preferences.iter() {
-            if *p as usize >= preferences.len() {
+            if *p as usize >= num_responders {
                 bail!("received invalid preferences: {:?}", preferences);
             }
         }
@@ -33,17 +43,23 @@ impl<'a> Proposer<'a> {
         Ok(Proposer {
             id,
             preferences,
-            preferences_index: preferences.len() - 1,
+            remaining: preferences.len(),
         })
     }
 
-    fn get_preference(&self) -> ResponderId {
-        self.preferences[self.preferences_index]
+    fn get_preference(&self) -> Option<ResponderId> {
+        // Proposers that have been rejected by every Responder they find
+        // acceptable have nobody left to propose to
+        if self.remaining > 0 {
+            Some(self.preferences[self.remaining - 1])
+        } else {
+            None
+        }
     }
 
     fn add_rejection(&mut self) -> Result<()> {
-        if self.preferences_index > 0 {
-            Ok(self.preferences_index -= 1)
+        if self.remaining > 0 {
+            Ok(self.remaining -= 1)
         } else {
             bail!("proposer {} received too many rejections", self.id);
         }
@@ -51,21 +67,29 @@ impl<'a> Proposer<'a> {
 }
 
 #[derive(Debug, Default, Eq, Hash, PartialEq)]
-pub struct Responder<'a> {
+pub struct Responder {
     id: ResponderId,
-    // List of Proposers ordered by ascending preference
-    preferences: &'a [ProposerId],
-    // Mapping from ProposerId -> preference
-    // Relies strongly on preferences and ProposerIds both being in the domain
-    // [0, n) where n is the number of Proposers
-    preferences_by_proposer: Vec<usize>,
+    // Mapping from ProposerId -> preference, or None if the Proposer is not acceptable
+    // Relies strongly on ProposerIds being in the domain [0, n) where n is the
+    // number of Proposers
+    preferences_by_proposer: Vec<Option<usize>>,
+    // Proposers we can never be matched with. Once one of them proposes we have to end
+    // up with someone we like more, or they would block the matching, so we stop
+    // accepting anyone we like at most as much
+    forbidden: BTreeSet<ProposerId>,
+    threshold: Option<usize>,
     proposals: BTreeSet<ProposerId>,
     accepted: Option<ProposerId>,
 }
 
-impl<'a> Responder<'a> {
-    fn new(id: u32, preferences: &'a [ProposerId]) -> Result<Self> {
-        if id as usize >= preferences.len() {
+impl Responder {
+    fn new(
+        id: u32,
+        preferences: &[ProposerId],
+        num_responders: usize,
+        num_proposers: usize,
+    ) -> Result<Self> {
+        if id as usize >= num_responders {
             bail!(
                 "received invalid responder input id: {} preferences: {:?}",
                 id,
@@ -74,33 +98,38 @@ impl<'a> Responder<'a> {
         }
 
         for p in preferences.iter() {
-            if *p as usize >= preferences.len() {
+            if *p as usize >= num_proposers {
                 bail!("received invalid preferences: {:?}", preferences);
             }
         }
 
-        let mut preferences_by_proposer: Vec<usize> = vec![0; preferences.len()];
+        let mut preferences_by_proposer: Vec<Option<usize>> = vec![None; num_proposers];
 
         for (index, p) in preferences.iter().enumerate() {
-            if preferences_by_proposer[*p as usize] != 0 {
+            if preferences_by_proposer[*p as usize].is_some() {
                 bail!(
                     "received duplicate preferences in responder: {} preferences: {:?}",
                     id,
                     preferences
                 );
             }
-            preferences_by_proposer[*p as usize] = index;
+            preferences_by_proposer[*p as usize] = Some(index);
         }
 
         Ok(Responder {
             id,
-            preferences,
             preferences_by_proposer,
+            forbidden: BTreeSet::new(),
+            threshold: None,
             proposals: BTreeSet::new(),
             accepted: None,
         })
     }
 
+    fn forbid(&mut self, proposer: ProposerId) {
+        self.forbidden.insert(proposer);
+    }
+
     fn add_proposal(&mut self, proposer: ProposerId) {
         self.proposals.insert(proposer);
     }
@@ -110,48 +139,54 @@ impl<'a> Responder<'a> {
             return None;
         }
 
-        let mut accept = *self
-            .proposals
-            .iter()
-            .nth(0)
-            .expect("proposals known to have elements");
-
-        if accept >= self.preferences.len() as ProposerId {
-            panic!(
-                "Received proposal {} that was outside of expected range {}",
-                accept,
-                self.preferences.len()
-            );
+        // Forbidden proposals are always rejected, and raise the bar for everyone else
+        for p in self.proposals.iter() {
+            if self.forbidden.contains(p) {
+                let preference = self.preferences_by_proposer.get(*p as usize).cloned();
+                self.threshold = std::cmp::max(self.threshold, preference.flatten());
+            }
         }
 
-        let mut accept_preference = self.preferences_by_proposer[accept as usize];
+        let mut accept: Option<(ProposerId, usize)> = None;
 
-        for p in self.proposals.iter().skip(1) {
-            if *p >= self.preferences.len() as ProposerId {
+        for p in self.proposals.iter() {
+            if *p as usize >= self.preferences_by_proposer.len() {
                 // We've received a proposer that we did not anticipate. Lets get out
                 panic!(
                     "Received proposal {} that was outside of expected range {}",
                     *p,
-                    self.preferences.len()
+                    self.preferences_by_proposer.len()
                 );
             }
 
-            let preference = self.preferences_by_proposer[*p as usize];
-            if preference > accept_preference {
-                accept = *p;
-                accept_preference = preference;
+            // Proposals from Proposers we don't find acceptable are always rejected
+            if let Some(preference) = self.preferences_by_proposer[*p as usize] {
+                if self
+                    .threshold
+                    .map_or(false, |threshold| preference <= threshold)
+                {
+                    continue;
+                }
+
+                match accept {
+                    Some((_, accept_preference)) if accept_preference > preference => {}
+                    _ => accept = Some((*p, preference)),
+                }
             }
         }
 
-        self.accepted = Some(accept);
+        self.accepted = accept.map(|(p, _)| p);
 
         // Reject every proposal we didn't accept
         let mut rejections = std::mem::replace(&mut self.proposals, BTreeSet::new());
-        rejections.remove(&accept);
 
-        // We want to preserve the invariant that proposals always contains the last proposal
-        // we tentatively accepted + future proposals
-        self.proposals.insert(accept);
+        if let Some(accept) = self.accepted {
+            rejections.remove(&accept);
+
+            // We want to preserve the invariant that proposals always contains the last proposal
+            // we tentatively accepted + future proposals
+            self.proposals.insert(accept);
+        }
 
         return Some(rejections.into_iter());
     }
@@ -160,25 +195,99 @@ impl<'a> Responder<'a> {
 pub fn stable_matching(
     proposers_input: &[ProposerInput],
     responders_input: &[ResponderInput],
+    side: Side,
 ) -> Result<HashMap<ProposerId, ResponderId>> {
+    Ok(stable_matching_with_unmatched(proposers_input, responders_input, side)?.matching)
+}
+
+pub fn stable_matching_with_unmatched(
+    proposers_input: &[ProposerInput],
+    responders_input: &[ResponderInput],
+    side: Side,
+) -> Result<MatchingResult> {
+    if side == Side::Responder {
+        // Let the Responders propose, and swap the result back around
+        let (proposers, responders) = swap_sides(proposers_input, responders_input)?;
+        let result = stable_matching_with_unmatched(&proposers, &responders, Side::Proposer)?;
+        return Ok(result.swap_sides());
+    }
+
+    let matching = deferred_acceptance(proposers_input, responders_input, &HashSet::new())?;
+
+    Ok(MatchingResult::new(
+        proposers_input,
+        responders_input,
+        matching,
+    ))
+}
+
+// Finds the stable matching that is best for the proposing side among the ones that
+// match every forced pair and no forbidden pair. Fails with an Infeasible error naming
+// the constraint that rules out every such matching
+pub fn stable_matching_with_constraints(
+    proposers_input: &[ProposerInput],
+    responders_input: &[ResponderInput],
+    side: Side,
+    constraints: &Constraints,
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    let matching = match side {
+        Side::Proposer => {
+            let excluded = constraints.excluded_pairs(proposers_input, responders_input);
+            deferred_acceptance(proposers_input, responders_input, &excluded)?
+        }
+        Side::Responder => {
+            let (proposers, responders) = swap_sides(proposers_input, responders_input)?;
+            let excluded = constraints
+                .swap_sides()
+                .excluded_pairs(&proposers, &responders);
+            deferred_acceptance(&proposers, &responders, &excluded)?
+                .into_iter()
+                .map(|(r, p)| (p, r))
+                .collect()
+        }
+    };
+
+    if let Some(infeasible) = constraints.violation(proposers_input, responders_input, &matching) {
+        bail!(infeasible);
+    }
+
+    Ok(matching)
+}
+
+fn deferred_acceptance(
+    proposers_input: &[ProposerInput],
+    responders_input: &[ResponderInput],
+    excluded: &HashSet<(ProposerId, ResponderId)>,
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    validate_one_to_one_input(proposers_input, responders_input)?;
+
+    let num_proposers = proposers_input.len();
+    let num_responders = responders_input.len();
     let mut proposers: Vec<_> = proposers_input
         .iter()
-        .map(|p| Proposer::new(p.id, &p.preferences))
+        .map(|p| Proposer::new(p.id, &p.preferences, num_proposers, num_responders))
         .collect::<Result<_>>()?;
     let mut responders: Vec<_> = responders_input
         .iter()
-        .map(|r| Responder::new(r.id, &r.preferences))
+        .map(|r| Responder::new(r.id, &r.preferences, num_responders, num_proposers))
         .collect::<Result<_>>()?;
     let mut unassigned: HashSet<_> = HashSet::from_iter(proposers_input.iter().map(|p| p.id));
 
+    for (p, r) in excluded.iter() {
+        if let Some(responder) = responders.get_mut(*r as usize) {
+            responder.forbid(*p);
+        }
+    }
+
     while !unassigned.is_empty() {
         // All unassigned Proposers propose to their highest ranked Responder
         // that has not already rejected them
         for p in unassigned.iter() {
             let proposer = &proposers[*p as usize];
-            let preference = proposer.get_preference();
-            let to_propose = &mut responders[preference as usize];
-            to_propose.add_proposal(*p);
+            if let Some(preference) = proposer.get_preference() {
+                let to_propose = &mut responders[preference as usize];
+                to_propose.add_proposal(*p);
+            }
         }
 
         unassigned = HashSet::new();
@@ -199,19 +308,144 @@ pub fn stable_matching(
     }
 
     // Return a mapping from ProposerId : ResponderId
-    Ok(HashMap::from_iter(responders.iter().map(|r| {
-        (
-            r.accepted
-                .expect("every responder should be matched with a proposer"),
-            r.id,
-        )
+    Ok(HashMap::from_iter(responders.iter().filter_map(|r| {
+        r.accepted.map(|accepted| (accepted, r.id))
     })))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
+    use rand::seq::SliceRandom;
+
+    use crate::input::{Constraints, Infeasible, MatchingInput, Side};
     #[test]
     fn basic_v0_test() {
         crate::input::basic_test(super::stable_matching);
     }
+
+    #[test]
+    fn extremal_v0_test() {
+        crate::input::extremal_test(super::stable_matching);
+    }
+
+    #[test]
+    fn incomplete_v0_test() {
+        crate::input::incomplete_test(super::stable_matching_with_unmatched);
+    }
+
+    #[test]
+    fn unbalanced_v0_test() {
+        crate::input::unbalanced_test(super::stable_matching_with_unmatched);
+    }
+
+    #[test]
+    fn invalid_v0_test() {
+        crate::input::invalid_input_test(super::stable_matching_with_unmatched);
+    }
+
+    #[test]
+    fn ties_v0_test() {
+        crate::input::ties_test(super::stable_matching);
+    }
+
+    #[test]
+    fn fixed_constraints_v0_test() {
+        crate::input::constraints_test(super::stable_matching_with_constraints);
+    }
+
+    #[test]
+    fn constraints_v0_test() {
+        let mut rng = rand::thread_rng();
+        for n in 1..12 {
+            for _ in 0..20 {
+                let (proposers, responders) = crate::input::random_incomplete_input(n, &mut rng);
+                let matchings: Vec<_> =
+                    crate::rotations::all_stable_matchings(&proposers, &responders)
+                        .unwrap()
+                        .collect();
+
+                // Mostly constrain pairs that show up in some stable matching, so that the
+                // constraints actually matter
+                let mut pairs: Vec<_> = matchings
+                    .iter()
+                    .flat_map(|m| m.iter().map(|(p, r)| (*p, *r)))
+                    .collect();
+                pairs.push((0, 0));
+                pairs.shuffle(&mut rng);
+                let constraints = Constraints::new(
+                    pairs.iter().take(1).cloned().collect(),
+                    pairs.iter().skip(1).take(2).cloned().collect(),
+                );
+
+                let feasible: Vec<_> = matchings
+                    .iter()
+                    .filter(|m| constraints.forced.iter().all(|(p, r)| m.get(p) == Some(r)))
+                    .filter(|m| {
+                        constraints
+                            .forbidden
+                            .iter()
+                            .all(|(p, r)| m.get(p) != Some(r))
+                    })
+                    .collect();
+
+                for side in [Side::Proposer, Side::Responder].iter() {
+                    let result = super::stable_matching_with_constraints(
+                        &proposers,
+                        &responders,
+                        *side,
+                        &constraints,
+                    );
+
+                    let matching = match result {
+                        Ok(matching) => matching,
+                        Err(e) => {
+                            assert_eq!(feasible.is_empty(), true);
+                            assert_eq!(e.downcast_ref::<Infeasible>().is_some(), true);
+                            continue;
+                        }
+                    };
+
+                    assert_eq!(feasible.contains(&&matching), true);
+
+                    // Nobody on the proposing side does better in another feasible matching
+                    for other in feasible.iter() {
+                        match side {
+                            Side::Proposer => {
+                                for (p, r) in other.iter() {
+                                    assert_eq!(
+                                        proposers[*p as usize].prefers_more(matching[p], *r),
+                                        false
+                                    );
+                                }
+                            }
+                            Side::Responder => {
+                                let inverted: HashMap<_, _> =
+                                    matching.iter().map(|(p, r)| (*r, *p)).collect();
+                                for (p, r) in other.iter() {
+                                    assert_eq!(
+                                        responders[*r as usize].prefers_more(inverted[r], *p),
+                                        false
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // Without constraints we just get the extremal matchings
+                assert_eq!(
+                    super::stable_matching_with_constraints(
+                        &proposers,
+                        &responders,
+                        Side::Proposer,
+                        &Constraints::default()
+                    )
+                    .unwrap(),
+                    super::stable_matching(&proposers, &responders, Side::Proposer).unwrap()
+                );
+            }
+        }
+    }
 }
@@ -2,22 +2,23 @@ use std::collections::btree_set::IntoIter;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::iter::FromIterator;
 
-use anyhow::{bail, Result};
+use anyhow::Result;
 
-use crate::input::{ProposerId, ProposerInput, ResponderId, ResponderInput};
+use crate::input::{
+    swap_sides, validate_one_to_one_input, MatchingResult, ProposerId, ProposerInput, ResponderId,
+    ResponderInput, Side,
+};
 
 #[derive(Debug, Default, Eq, Hash, PartialEq)]
 struct Proposer {
-    id: ProposerId,
     // Map from ResponderId -> preference
     preferences: BTreeMap<ResponderId, usize>,
     rejections: BTreeSet<ResponderId>,
 }
 
 impl Proposer {
-    fn new(id: ProposerId, preferences: &[ResponderId]) -> Self {
+    fn new(preferences: &[ResponderId]) -> Self {
         Proposer {
-            id,
             preferences: preferences
                 .iter()
                 .enumerate()
@@ -27,24 +28,16 @@ impl Proposer {
         }
     }
 
-    fn get_preference(&self) -> Result<ResponderId> {
+    fn get_preference(&self) -> Option<ResponderId> {
         // Get the most preferred Responder that has not already rejected
         // Note that even though we are using a "filter", we are
         // computing (preferences - rejections) or an antijoin
-        let preference = self
-            .preferences
+        // Proposers that every acceptable Responder rejected get None
+        self.preferences
             .iter()
             .filter(|(r, _)| !self.rejections.contains(r))
-            .max_by_key(|(_, pref)| *pref);
-
-        if let Some((responder, _)) = preference {
-            Ok(*responder)
-        } else {
-            bail!(
-                "proposer {} has no available responders to propose to",
-                self.id
-            );
-        }
+            .max_by_key(|(_, pref)| *pref)
+            .map(|(r, _)| *r)
     }
 
     fn add_rejection(&mut self, responder: ResponderId) {
@@ -105,10 +98,28 @@ impl Responder {
 pub fn stable_matching(
     proposers_input: &[ProposerInput],
     responders_input: &[ResponderInput],
+    side: Side,
 ) -> Result<HashMap<ProposerId, ResponderId>> {
+    Ok(stable_matching_with_unmatched(proposers_input, responders_input, side)?.matching)
+}
+
+pub fn stable_matching_with_unmatched(
+    proposers_input: &[ProposerInput],
+    responders_input: &[ResponderInput],
+    side: Side,
+) -> Result<MatchingResult> {
+    if side == Side::Responder {
+        // Let the Responders propose, and swap the result back around
+        let (proposers, responders) = swap_sides(proposers_input, responders_input)?;
+        let result = stable_matching_with_unmatched(&proposers, &responders, Side::Proposer)?;
+        return Ok(result.swap_sides());
+    }
+
+    validate_one_to_one_input(proposers_input, responders_input)?;
+
     let mut proposers: HashMap<_, _> = proposers_input
         .iter()
-        .map(|p| (p.id, Proposer::new(p.id, &p.preferences)))
+        .map(|p| (p.id, Proposer::new(&p.preferences)))
         .collect();
     let mut responders: HashMap<_, _> = responders_input
         .iter()
@@ -121,11 +132,12 @@ pub fn stable_matching(
         // that has not already rejected them
         for p in unassigned.iter() {
             let proposer = &proposers[p];
-            let preference = proposer.get_preference()?;
-            let to_propose = responders
-                .get_mut(&preference)
-                .expect("responder known to exist");
-            to_propose.add_proposal(*p);
+            if let Some(preference) = proposer.get_preference() {
+                let to_propose = responders
+                    .get_mut(&preference)
+                    .expect("responder known to exist");
+                to_propose.add_proposal(*p);
+            }
         }
 
         unassigned = HashSet::new();
@@ -145,14 +157,19 @@ pub fn stable_matching(
         }
     }
 
-    // Return a mapping from ProposerId : ResponderId
-    Ok(HashMap::from_iter(responders.iter().map(|(_, r)| {
-        (
-            r.accepted
-                .expect("every responder should be matched with a proposer"),
-            r.id,
-        )
-    })))
+    // Return a mapping from ProposerId : ResponderId, along with everyone
+    // that could not be matched
+    let matching = HashMap::from_iter(
+        responders
+            .iter()
+            .filter_map(|(_, r)| r.accepted.map(|accepted| (accepted, r.id))),
+    );
+
+    Ok(MatchingResult::new(
+        proposers_input,
+        responders_input,
+        matching,
+    ))
 }
 
 #[cfg(test)]
@@ -161,4 +178,29 @@ mod tests {
     fn basic_v1_test() {
         crate::input::basic_test(super::stable_matching);
     }
+
+    #[test]
+    fn extremal_v1_test() {
+        crate::input::extremal_test(super::stable_matching);
+    }
+
+    #[test]
+    fn incomplete_v1_test() {
+        crate::input::incomplete_test(super::stable_matching_with_unmatched);
+    }
+
+    #[test]
+    fn unbalanced_v1_test() {
+        crate::input::unbalanced_test(super::stable_matching_with_unmatched);
+    }
+
+    #[test]
+    fn invalid_v1_test() {
+        crate::input::invalid_input_test(super::stable_matching_with_unmatched);
+    }
+
+    #[test]
+    fn ties_v1_test() {
+        crate::input::ties_test(super::stable_matching);
+    }
 }
@@ -2,21 +2,22 @@ use std::collections::btree_set::IntoIter;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::iter::FromIterator;
 
-use anyhow::{bail, Result};
+use anyhow::Result;
 
-use crate::input::{ProposerId, ProposerInput, ResponderId, ResponderInput};
+use crate::input::{
+    swap_sides, validate_one_to_one_input, MatchingResult, ProposerId, ProposerInput, ResponderId,
+    ResponderInput, Side,
+};
 
 #[derive(Debug, Default, Eq, Hash, PartialEq)]
 struct Proposer {
-    id: ProposerId,
     // Map from ResponderId -> preference
     preferences: BTreeMap<ResponderId, usize>,
 }
 
 impl Proposer {
-    fn new(id: ProposerId, preferences: &[ResponderId]) -> Self {
+    fn new(preferences: &[ResponderId]) -> Self {
         Proposer {
-            id,
             preferences: preferences
                 .iter()
                 .enumerate()
@@ -25,24 +26,16 @@ impl Proposer {
         }
     }
 
-    fn get_proposal(&self, rejections: &BTreeSet<ProposerId>) -> Result<ResponderId> {
+    fn get_proposal(&self, rejections: &BTreeSet<ProposerId>) -> Option<ResponderId> {
         // Get the most preferred Responder that has not already rejected
         // Note that even though we are using a "filter", we are
         // computing (preferences - rejections) or an antijoin
-        let proposal = self
-            .preferences
+        // Proposers that every acceptable Responder rejected get None
+        self.preferences
             .iter()
             .filter(|(r, _)| !rejections.contains(r))
-            .max_by_key(|(_, pref)| *pref);
-
-        if let Some((responder, _)) = proposal {
-            Ok(*responder)
-        } else {
-            bail!(
-                "proposer {} has no available responders to propose to",
-                self.id
-            );
-        }
+            .max_by_key(|(_, pref)| *pref)
+            .map(|(r, _)| *r)
     }
 }
 
@@ -78,10 +71,28 @@ impl Responder {
 pub fn stable_matching(
     proposers_input: &[ProposerInput],
     responders_input: &[ResponderInput],
+    side: Side,
 ) -> Result<HashMap<ProposerId, ResponderId>> {
+    Ok(stable_matching_with_unmatched(proposers_input, responders_input, side)?.matching)
+}
+
+pub fn stable_matching_with_unmatched(
+    proposers_input: &[ProposerInput],
+    responders_input: &[ResponderInput],
+    side: Side,
+) -> Result<MatchingResult> {
+    if side == Side::Responder {
+        // Let the Responders propose, and swap the result back around
+        let (proposers, responders) = swap_sides(proposers_input, responders_input)?;
+        let result = stable_matching_with_unmatched(&proposers, &responders, Side::Proposer)?;
+        return Ok(result.swap_sides());
+    }
+
+    validate_one_to_one_input(proposers_input, responders_input)?;
+
     let proposers: HashMap<_, _> = proposers_input
         .iter()
-        .map(|p| (p.id, Proposer::new(p.id, &p.preferences)))
+        .map(|p| (p.id, Proposer::new(&p.preferences)))
         .collect();
     let responders: HashMap<_, _> = responders_input
         .iter()
@@ -93,18 +104,12 @@ pub fn stable_matching(
         .collect();
 
     loop {
-        // Gather a proposal from every Proposer
+        // Gather a proposal from every Proposer that still has someone to propose to
         let initial_proposals: HashMap<ProposerId, ResponderId> = proposers
             .iter()
             // This map is effectively a join with rejections
             .map(|(p_id, p)| (p_id, p, &rejections[p_id]))
-            .map(|(p_id, p, rejections)| {
-                (
-                    *p_id,
-                    p.get_proposal(rejections)
-                        .expect("every proposer should have a proposal"),
-                )
-            })
+            .filter_map(|(p_id, p, rejections)| p.get_proposal(rejections).map(|r| (*p_id, r)))
             .collect();
 
         // Convert the proposals to become a multimap (represented by a HashMap
@@ -137,10 +142,14 @@ pub fn stable_matching(
             }
         }
 
-        // If the matching looks complete - lets exit
+        // If every proposal was accepted the matching is stable - lets exit
         // We won't need this bit in dataflow code
-        if matching.len() == proposers.len() {
-            return Ok(matching);
+        if matching.len() == initial_proposals.len() {
+            return Ok(MatchingResult::new(
+                proposers_input,
+                responders_input,
+                matching,
+            ));
         }
 
         // Update the set of rejections to include every proposal
@@ -163,4 +172,29 @@ mod tests {
     fn basic_v2_test() {
         crate::input::basic_test(super::stable_matching);
     }
+
+    #[test]
+    fn extremal_v2_test() {
+        crate::input::extremal_test(super::stable_matching);
+    }
+
+    #[test]
+    fn incomplete_v2_test() {
+        crate::input::incomplete_test(super::stable_matching_with_unmatched);
+    }
+
+    #[test]
+    fn unbalanced_v2_test() {
+        crate::input::unbalanced_test(super::stable_matching_with_unmatched);
+    }
+
+    #[test]
+    fn invalid_v2_test() {
+        crate::input::invalid_input_test(super::stable_matching_with_unmatched);
+    }
+
+    #[test]
+    fn ties_v2_test() {
+        crate::input::ties_test(super::stable_matching);
+    }
 }
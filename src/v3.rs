@@ -1,5 +1,7 @@
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
+use anyhow::{anyhow, bail, Result};
 use differential_dataflow::input::Input;
 use differential_dataflow::lattice::Lattice;
 use differential_dataflow::operators::arrange::ArrangeByKey;
@@ -12,57 +14,188 @@ use timely::dataflow::ProbeHandle;
 use timely::dataflow::Scope;
 use timely::order::Product;
 
-use crate::input::{ProposerInput, ResponderInput};
+use crate::input::{
+    validate_one_to_one_input, Constraints, ProposerId, ProposerInput, ResponderId, ResponderInput,
+    Side,
+};
 
-pub fn stable_matching(proposers: Vec<ProposerInput>, responders: Vec<ResponderInput>) {
-    timely::execute_from_args(std::env::args(), move |worker| {
+// The part of a dataflow engine that computes the matching. Loading the input, letting
+// either side propose, and checking and collecting the result is shared by every engine
+pub trait MatchingDataflow {
+    fn generate_matching<G: Scope>(
+        proposers: &Collection<G, (u32, u32, usize)>,
+        responders: &Collection<G, (u32, u32, usize)>,
+        excluded: &Collection<G, (u32, u32)>,
+    ) -> Collection<G, (u32, u32)>
+    where
+        G::Timestamp: Lattice + Ord;
+}
+
+struct Engine;
+
+impl MatchingDataflow for Engine {
+    fn generate_matching<G: Scope>(
+        proposers: &Collection<G, (u32, u32, usize)>,
+        responders: &Collection<G, (u32, u32, usize)>,
+        excluded: &Collection<G, (u32, u32)>,
+    ) -> Collection<G, (u32, u32)>
+    where
+        G::Timestamp: Lattice + Ord,
+    {
+        generate_matching(proposers, responders, excluded)
+    }
+}
+
+pub fn stable_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    side: Side,
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    stable_matching_with_constraints(proposers, responders, side, &Constraints::default())
+}
+
+// Finds the stable matching that is best for the proposing side among the ones that
+// match every forced pair and no forbidden pair. Fails with an Infeasible error naming
+// the constraint that rules out every such matching
+pub fn stable_matching_with_constraints(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    side: Side,
+    constraints: &Constraints,
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    run_dataflow::<Engine>(proposers, responders, side, constraints)
+}
+
+pub fn run_dataflow<D: MatchingDataflow>(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    side: Side,
+    constraints: &Constraints,
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    validate_one_to_one_input(proposers, responders)?;
+
+    let proposer_preferences: Vec<(u32, u32, usize)> = proposers
+        .iter()
+        .flat_map(|p| {
+            p.preferences
+                .iter()
+                .enumerate()
+                .map(move |(preference, responder)| (p.id, *responder, preference))
+        })
+        .collect();
+    let responder_preferences: Vec<(u32, u32, usize)> = responders
+        .iter()
+        .flat_map(|r| {
+            r.preferences
+                .iter()
+                .enumerate()
+                .map(move |(preference, proposer)| (r.id, *proposer, preference))
+        })
+        .collect();
+    let excluded: Vec<_> = constraints
+        .excluded_pairs(proposers, responders)
+        .into_iter()
+        .collect();
+
+    // How many times every (proposer, responder) pair is in the result, summed over the
+    // changes every worker sees
+    let counts: Arc<Mutex<HashMap<(ProposerId, ResponderId), isize>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    let worker_counts = counts.clone();
+
+    let guards = timely::execute_from_args(std::env::args(), move |worker| {
         let mut probe = ProbeHandle::new();
+        let counts = worker_counts.clone();
 
-        let (mut proposers_input, mut responders_input) = worker.dataflow(|scope| {
-            let (proposers_input, proposers) = scope.new_collection();
-            let (responders_input, responders) = scope.new_collection();
+        let (mut proposers_input, mut responders_input, mut excluded_input) =
+            worker.dataflow(|scope| {
+                let (proposers_input, proposers) = scope.new_collection();
+                let (responders_input, responders) = scope.new_collection();
+                // (proposer, responder) pairs that can't be matched
+                let (excluded_input, excluded) = scope.new_collection();
 
-            let matching = generate_matching(&proposers, &responders);
+                let matching = match side {
+                    Side::Proposer => D::generate_matching(&proposers, &responders, &excluded),
+                    // Responders make the proposals, so flip the (responder, proposer) pairs
+                    // back around before validating them
+                    Side::Responder => D::generate_matching(
+                        &responders,
+                        &proposers,
+                        &excluded.map(|(proposer, responder)| (responder, proposer)),
+                    )
+                    .map(|(responder, proposer)| (proposer, responder)),
+                };
 
-            let result = matching
-                //.inspect(|x| println!("result: {:?}", x))
-                .probe_with(&mut probe);
+                matching
+                    .inspect(move |((proposer, responder), _, diff)| {
+                        let mut counts = counts.lock().expect("counts known to be unpoisoned");
+                        *counts.entry((*proposer, *responder)).or_insert(0) += *diff;
+                    })
+                    .probe_with(&mut probe);
 
-            let invalid = validate_matching(&proposers, &responders, &matching)
-                .probe_with(&mut probe)
-                .assert_empty();
+                // Excluded pairs still count when checking stability, and if one of them
+                // blocks the matching the constraints can't be met. Any other blocking pair
+                // is a bug
+                validate_matching(&proposers, &responders, &matching)
+                    .map(|pair| (pair, ()))
+                    .antijoin(&excluded)
+                    .probe_with(&mut probe)
+                    .assert_empty();
 
-            (proposers_input, responders_input)
-        });
+                (proposers_input, responders_input, excluded_input)
+            });
 
-        for proposer in &proposers {
-            for (preference, responder) in proposer.preferences.iter().enumerate() {
-                proposers_input.insert((proposer.id, *responder, preference));
-            }
+        for preference in proposer_preferences.iter() {
+            proposers_input.insert(*preference);
         }
 
-        for responder in &responders {
-            for (preference, proposer) in responder.preferences.iter().enumerate() {
-                responders_input.insert((responder.id, *proposer, preference));
-            }
+        for preference in responder_preferences.iter() {
+            responders_input.insert(*preference);
+        }
+
+        for pair in excluded.iter() {
+            excluded_input.insert(*pair);
         }
 
         proposers_input.advance_to(1 as u32);
         responders_input.advance_to(1 as u32);
+        excluded_input.advance_to(1 as u32);
 
         proposers_input.flush();
         responders_input.flush();
+        excluded_input.flush();
 
         worker.step_while(|| {
-            probe.less_than(proposers_input.time()) || probe.less_than(responders_input.time())
+            probe.less_than(proposers_input.time())
+                || probe.less_than(responders_input.time())
+                || probe.less_than(excluded_input.time())
         });
     })
-    .expect("completed without errors");
+    .map_err(|e| anyhow!(e))?;
+
+    for result in guards.join() {
+        result.map_err(|e| anyhow!(e))?;
+    }
+
+    let matching: HashMap<ProposerId, ResponderId> = counts
+        .lock()
+        .expect("counts known to be unpoisoned")
+        .iter()
+        .filter(|(_, count)| **count > 0)
+        .map(|(pair, _)| *pair)
+        .collect();
+
+    if let Some(infeasible) = constraints.violation(proposers, responders, &matching) {
+        bail!(infeasible);
+    }
+
+    Ok(matching)
 }
 
 fn generate_matching<G: Scope>(
     proposers: &Collection<G, (u32, u32, usize)>,
     responders: &Collection<G, (u32, u32, usize)>,
+    excluded: &Collection<G, (u32, u32)>,
 ) -> Collection<G, (u32, u32)>
 where
     G::Timestamp: Lattice + Ord,
@@ -85,6 +218,9 @@ where
             // Bring the responder and proposer collections into the iterative computation
             let proposers = proposers.enter(&rejections_inner.scope());
             let responders = responders.enter(&rejections_inner.scope());
+            let excluded = excluded
+                .map(|(proposer, responder)| (responder, proposer))
+                .enter(&rejections_inner.scope());
 
             //rejections_inner
             //    .inspect(|x| println!("rejections (proposer, reponder): {:?}", x));
@@ -113,10 +249,37 @@ where
                 .map(|(proposer, (preference, responder))| ((responder, proposer), ()));
             //.inspect(|x| println!("proposal (nested): (suited, suitor)  {:?}", x));
 
-            let accepted = responders
-                .map(|(responder, proposer, preference)| ((responder, proposer), preference))
+            let responder_preferences = responders
+                .map(|(responder, proposer, preference)| ((responder, proposer), preference));
+
+            // Once an excluded proposer proposes, a responder has to end up with someone
+            // it likes more, so the best excluded proposal it ever received sets a threshold
+            // every proposal it accepts has to beat
+            let thresholds = rejections_inner
+                .map(|(proposer, responder)| ((responder, proposer), ()))
+                .concat(&proposals)
+                .semijoin(&excluded)
+                .join(&responder_preferences)
+                .map(|((responder, _proposer), ((), preference))| (responder, preference))
+                .reduce(|_responder, input, output| {
+                    // Values come in sorted, so the last one is the best
+                    output.push((*input[input.len() - 1].0, 1));
+                });
+
+            let candidates = responder_preferences
                 .join(&proposals)
-                .map(|((responder, proposer), preference)| (responder, (preference, proposer)))
+                .antijoin(&excluded)
+                .map(|((responder, proposer), (preference, ()))| {
+                    (responder, (preference, proposer))
+                });
+
+            let below_threshold = candidates
+                .join(&thresholds)
+                .filter(|(_, ((preference, _), threshold))| preference <= threshold)
+                .map(|(responder, (candidate, _))| (responder, candidate));
+
+            let accepted = candidates
+                .concat(&below_threshold.negate())
                 // Now grab the best proposal each responder received
                 .reduce(|_responder, input, output| {
                     let mut max_index = 0;
@@ -191,14 +354,61 @@ where
 
 #[cfg(test)]
 mod tests {
+    use crate::input::{Infeasible, Side};
+
     #[test]
     fn basic_v3_test() {
+        crate::input::basic_test(super::stable_matching);
+    }
+
+    #[test]
+    fn extremal_v3_test() {
+        crate::input::extremal_test(super::stable_matching);
+    }
+
+    #[test]
+    fn invalid_v3_test() {
+        crate::input::invalid_input_test(super::stable_matching);
+    }
+
+    #[test]
+    fn ties_v3_test() {
+        crate::input::ties_test(super::stable_matching);
+    }
+
+    #[test]
+    fn constraints_v3_test() {
+        crate::input::constraints_test(super::stable_matching_with_constraints);
+
+        // Random constraints give the same matching, or blame the same constraint, as v0
         let mut rng = rand::thread_rng();
-        for n in 1..100 {
-            println!("starting test {}", n);
-            let (proposers, responders) = crate::input::random_input(n, &mut rng);
+        for n in 1..30 {
+            let (proposers, responders) = crate::input::random_incomplete_input(n, &mut rng);
+            let constraints = crate::input::random_constraints(&proposers, &responders, &mut rng);
 
-            super::stable_matching(proposers, responders);
+            for side in [Side::Proposer, Side::Responder].iter() {
+                let result = super::stable_matching_with_constraints(
+                    &proposers,
+                    &responders,
+                    *side,
+                    &constraints,
+                );
+                let expected = crate::v0::stable_matching_with_constraints(
+                    &proposers,
+                    &responders,
+                    *side,
+                    &constraints,
+                );
+
+                match (result, expected) {
+                    (Ok(matching), Ok(expected)) => assert_eq!(matching, expected),
+                    (Err(e), Err(expected)) => assert_eq!(
+                        e.downcast_ref::<Infeasible>(),
+                        expected.downcast_ref::<Infeasible>()
+                    ),
+                    (result, expected) => panic!("got {:?} expected {:?}", result, expected),
+                }
+            }
         }
     }
 }
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use differential_dataflow::input::Input;
+use anyhow::Result;
 use differential_dataflow::lattice::Lattice;
 use differential_dataflow::operators::arrange::ArrangeByKey;
 use differential_dataflow::operators::iterate::Variable;
@@ -8,61 +8,51 @@ use differential_dataflow::operators::{Consolidate, Join, Reduce};
 use differential_dataflow::trace::Cursor;
 use differential_dataflow::trace::TraceReader;
 use differential_dataflow::Collection;
-use timely::dataflow::ProbeHandle;
 use timely::dataflow::Scope;
 use timely::order::Product;
 
-use crate::input::{ProposerInput, ResponderInput};
-
-pub fn stable_matching(proposers: Vec<ProposerInput>, responders: Vec<ResponderInput>) {
-    timely::execute_from_args(std::env::args(), move |worker| {
-        let mut probe = ProbeHandle::new();
-
-        let (mut proposers_input, mut responders_input) = worker.dataflow(|scope| {
-            let (proposers_input, proposers) = scope.new_collection();
-            let (responders_input, responders) = scope.new_collection();
-
-            let matching = generate_matching(&proposers, &responders);
-
-            let result = matching
-                //.inspect(|x| println!("result: {:?}", x))
-                .probe_with(&mut probe);
-
-            let invalid = crate::v3::validate_matching(&proposers, &responders, &matching)
-                .probe_with(&mut probe)
-                .assert_empty();
-
-            (proposers_input, responders_input)
-        });
-
-        for proposer in &proposers {
-            for (preference, responder) in proposer.preferences.iter().enumerate() {
-                proposers_input.insert((proposer.id, *responder, preference));
-            }
-        }
-
-        for responder in &responders {
-            for (preference, proposer) in responder.preferences.iter().enumerate() {
-                responders_input.insert((responder.id, *proposer, preference));
-            }
-        }
-
-        proposers_input.advance_to(1 as u32);
-        responders_input.advance_to(1 as u32);
+use crate::input::{Constraints, ProposerId, ProposerInput, ResponderId, ResponderInput, Side};
+use crate::v3::MatchingDataflow;
+
+struct Engine;
+
+impl MatchingDataflow for Engine {
+    fn generate_matching<G: Scope>(
+        proposers: &Collection<G, (u32, u32, usize)>,
+        responders: &Collection<G, (u32, u32, usize)>,
+        excluded: &Collection<G, (u32, u32)>,
+    ) -> Collection<G, (u32, u32)>
+    where
+        G::Timestamp: Lattice + Ord,
+    {
+        generate_matching(proposers, responders, excluded)
+    }
+}
 
-        proposers_input.flush();
-        responders_input.flush();
+pub fn stable_matching(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    side: Side,
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    stable_matching_with_constraints(proposers, responders, side, &Constraints::default())
+}
 
-        worker.step_while(|| {
-            probe.less_than(proposers_input.time()) || probe.less_than(responders_input.time())
-        });
-    })
-    .expect("completed without errors");
+// Finds the stable matching that is best for the proposing side among the ones that
+// match every forced pair and no forbidden pair. Fails with an Infeasible error naming
+// the constraint that rules out every such matching
+pub fn stable_matching_with_constraints(
+    proposers: &[ProposerInput],
+    responders: &[ResponderInput],
+    side: Side,
+    constraints: &Constraints,
+) -> Result<HashMap<ProposerId, ResponderId>> {
+    crate::v3::run_dataflow::<Engine>(proposers, responders, side, constraints)
 }
 
 fn generate_matching<G: Scope>(
     proposers: &Collection<G, (u32, u32, usize)>,
     responders: &Collection<G, (u32, u32, usize)>,
+    excluded: &Collection<G, (u32, u32)>,
 ) -> Collection<G, (u32, u32)>
 where
     G::Timestamp: Lattice + Ord,
@@ -104,10 +94,42 @@ where
                 });
             //.inspect(|x| println!("proposal (nested): (suited, suitor)  {:?}", x));
 
-            let accepted = proposals
+            let all = active.enter(&active_inner.scope());
+            let excluded = excluded.enter(&active_inner.scope());
+
+            // Once an excluded proposer proposes, a responder has to end up with someone
+            // it likes more, so the best excluded proposal it ever received sets a threshold
+            // every proposal it accepts has to beat. Rejected proposals have already left
+            // the active set
+            let thresholds = all
+                .concat(&active_inner.negate())
+                .concat(&proposals)
+                .map(|(proposer, _, responder, responder_pref)| {
+                    ((proposer, responder), responder_pref)
+                })
+                .semijoin(&excluded)
+                .map(|((_, responder), responder_pref)| (responder, responder_pref))
+                .reduce(|_responder, input, output| {
+                    // Values come in sorted, so the last one is the best
+                    output.push((*input[input.len() - 1].0, 1));
+                });
+
+            let candidates = proposals
                 .map(|(proposer, proposer_pref, responder, responder_pref)| {
-                    (responder, (responder_pref, proposer, proposer_pref))
+                    ((proposer, responder), (proposer_pref, responder_pref))
                 })
+                .antijoin(&excluded)
+                .map(|((proposer, responder), (proposer_pref, responder_pref))| {
+                    (responder, (responder_pref, proposer, proposer_pref))
+                });
+
+            let below_threshold = candidates
+                .join(&thresholds)
+                .filter(|(_, ((responder_pref, _, _), threshold))| responder_pref <= threshold)
+                .map(|(responder, (candidate, _))| (responder, candidate));
+
+            let accepted = candidates
+                .concat(&below_threshold.negate())
                 // Now grab the best proposal each responder received
                 .reduce(|_responder, input, output| {
                     let mut max_index = 0;
@@ -138,14 +160,61 @@ where
 
 #[cfg(test)]
 mod tests {
+    use crate::input::{Infeasible, Side};
+
     #[test]
     fn basic_v4_test() {
-        let mut rng = rand::thread_rng();
-        for n in 1..100 {
-            println!("starting test {}", n);
-            let (proposers, responders) = crate::input::random_input(n, &mut rng);
+        crate::input::basic_test(super::stable_matching);
+    }
+
+    #[test]
+    fn extremal_v4_test() {
+        crate::input::extremal_test(super::stable_matching);
+    }
 
-            super::stable_matching(proposers, responders);
+    #[test]
+    fn invalid_v4_test() {
+        crate::input::invalid_input_test(super::stable_matching);
+    }
+
+    #[test]
+    fn ties_v4_test() {
+        crate::input::ties_test(super::stable_matching);
+    }
+
+    #[test]
+    fn constraints_v4_test() {
+        crate::input::constraints_test(super::stable_matching_with_constraints);
+
+        // Random constraints give the same matching, or blame the same constraint, as v0
+        let mut rng = rand::thread_rng();
+        for n in 1..30 {
+            let (proposers, responders) = crate::input::random_incomplete_input(n, &mut rng);
+            let constraints = crate::input::random_constraints(&proposers, &responders, &mut rng);
+
+            for side in [Side::Proposer, Side::Responder].iter() {
+                let result = super::stable_matching_with_constraints(
+                    &proposers,
+                    &responders,
+                    *side,
+                    &constraints,
+                );
+                let expected = crate::v0::stable_matching_with_constraints(
+                    &proposers,
+                    &responders,
+                    *side,
+                    &constraints,
+                );
+
+                match (result, expected) {
+                    (Ok(matching), Ok(expected)) => assert_eq!(matching, expected),
+                    (Err(e), Err(expected)) => assert_eq!(
+                        e.downcast_ref::<Infeasible>(),
+                        expected.downcast_ref::<Infeasible>()
+                    ),
+                    (result, expected) => panic!("got {:?} expected {:?}", result, expected),
+                }
+            }
         }
     }
 }